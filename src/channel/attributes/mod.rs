@@ -0,0 +1,126 @@
+use std::any::{Any, TypeId};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use crate::util::LockExt;
+
+type Attribute = Arc<dyn Any + Send + Sync>;
+
+/// A typed key for the attributes of a channel.
+/// Keys are usually declared as constants (`const SESSION: AttributeKey<Session> = AttributeKey::new("session")`),
+/// so every part of the application uses the same one.
+/// Keys with the same name but different types refer to different attributes
+pub struct AttributeKey<T> where T: Any + Send + Sync {
+    name: &'static str,
+    _value: PhantomData<fn() -> T>,
+}
+
+/// The attributes attached to a channel, which allow handlers to keep per channel state
+/// (session info, the identity of the peer, protocol state, ...) in the channel itself.
+/// Values are stored in an [Arc], so state that has to be changed after being inserted should
+/// use interior mutability (a Mutex or atomics, for example)
+#[derive(Default)]
+pub struct AttributeMap {
+    attributes: Mutex<BTreeMap<(&'static str, TypeId), Attribute>>,
+}
+
+impl<T> AttributeKey<T> where T: Any + Send + Sync {
+    pub const fn new(name: &'static str) -> Self {
+        AttributeKey {
+            name,
+            _value: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn map_key(&self) -> (&'static str, TypeId) {
+        (self.name, TypeId::of::<T>())
+    }
+}
+
+impl<T> Clone for AttributeKey<T> where T: Any + Send + Sync {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for AttributeKey<T> where T: Any + Send + Sync {}
+
+impl<T> Debug for AttributeKey<T> where T: Any + Send + Sync {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AttributeKey({})", self.name)
+    }
+}
+
+impl AttributeMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get<T>(&self, key: &AttributeKey<T>) -> Option<Arc<T>> where T: Any + Send + Sync {
+        self.attributes.lock_safe().get(&key.map_key())
+            .cloned()
+            .map(downcast)
+    }
+
+    /// Set the value of the attribute, returning the previous one
+    pub fn insert<T>(&self, key: &AttributeKey<T>, value: T) -> Option<Arc<T>> where T: Any + Send + Sync {
+        self.attributes.lock_safe().insert(key.map_key(), Arc::new(value))
+            .map(downcast)
+    }
+
+    /// Get the value of the attribute, setting it with the given function if it's not set yet.
+    /// The function is called with the attributes locked, so it must not access them
+    pub fn get_or_insert_with<T, F>(&self, key: &AttributeKey<T>, init: F) -> Arc<T>
+        where T: Any + Send + Sync,
+              F: FnOnce() -> T {
+        let value = self.attributes.lock_safe()
+            .entry(key.map_key())
+            .or_insert_with(|| Arc::new(init()))
+            .clone();
+
+        downcast(value)
+    }
+
+    pub fn remove<T>(&self, key: &AttributeKey<T>) -> Option<Arc<T>> where T: Any + Send + Sync {
+        self.attributes.lock_safe().remove(&key.map_key())
+            .map(downcast)
+    }
+
+    pub fn contains<T>(&self, key: &AttributeKey<T>) -> bool where T: Any + Send + Sync {
+        self.attributes.lock_safe().contains_key(&key.map_key())
+    }
+}
+
+/// The type is part of the key, so the values are always of the expected type
+fn downcast<T>(value: Arc<dyn Any + Send + Sync>) -> Arc<T> where T: Any + Send + Sync {
+    value.downcast().unwrap_or_else(|_| unreachable!("Attribute stored with the wrong type"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: AttributeKey<String> = AttributeKey::new("user");
+    const USER_ID: AttributeKey<u64> = AttributeKey::new("user");
+
+    #[test]
+    fn typed_attributes() {
+        let attributes = AttributeMap::new();
+
+        assert_eq!(attributes.get(&USER), None);
+        assert_eq!(attributes.insert(&USER, "alice".to_string()), None);
+        assert_eq!(attributes.insert(&USER_ID, 7), None);
+
+        assert_eq!(attributes.get(&USER).as_deref(), Some(&"alice".to_string()));
+        assert_eq!(*attributes.get_or_insert_with(&USER_ID, || 9), 7);
+
+        assert_eq!(attributes.remove(&USER).as_deref(), Some(&"alice".to_string()));
+        assert!(!attributes.contains(&USER));
+        assert!(attributes.contains(&USER_ID));
+    }
+}
@@ -0,0 +1,111 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use crate::util::random_u64;
+
+/// How many of the low bits of an instance prefixed id are used for the sequence number.
+/// The remaining high bits hold the random prefix of this process
+const SEQUENCE_BITS: u32 = 40;
+
+/// The sequence is shared by every event group (and connector) in the process,
+/// so ids are never repeated, regardless of where the channel lives
+static NEXT_SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+/// The random prefix of this process, generated on first use
+static INSTANCE_PREFIX: OnceLock<usize> = OnceLock::new();
+
+/// How the ids of the channels are generated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChannelIdScheme {
+    /// Ids are sequential, and unique within this process
+    #[default]
+    Sequential,
+    /// The high bits of the id are a random prefix chosen when the process starts,
+    /// so ids are (with a very high probability) also unique across restarts and processes.
+    /// Only available when usize is 64 bits wide, otherwise we fall back to sequential ids
+    InstancePrefixed,
+}
+
+/// Allocate a new channel id.
+/// The id is unique across all of the event groups of this process, so it can be
+/// used as the key of the channel in the poller and to correlate logs
+pub(crate) fn next_channel_id(scheme: ChannelIdScheme) -> usize {
+    let sequence = NEXT_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+
+    match scheme {
+        ChannelIdScheme::InstancePrefixed if usize::BITS > SEQUENCE_BITS => {
+            (sequence & sequence_mask()) | instance_prefix()
+        }
+        _ => sequence
+    }
+}
+
+/// Get the sequence number part of a channel id
+pub fn channel_id_sequence(channel_id: usize) -> usize {
+    if usize::BITS > SEQUENCE_BITS {
+        channel_id & sequence_mask()
+    } else {
+        channel_id
+    }
+}
+
+fn sequence_mask() -> usize {
+    (1 << SEQUENCE_BITS) - 1
+}
+
+fn instance_prefix() -> usize {
+    *INSTANCE_PREFIX.get_or_init(|| {
+        let prefix = (random_u64() as usize) & !sequence_mask();
+
+        //usize::MAX is reserved by the poller for its own notifications,
+        //so we make sure no id can ever reach it
+        if prefix == !sequence_mask() {
+            prefix & !(1 << (usize::BITS - 1))
+        } else {
+            prefix
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use super::*;
+
+    #[test]
+    fn ids_are_unique_across_threads() {
+        let threads: Vec<_> = (0..4).map(|_| std::thread::spawn(|| {
+            (0..1000).map(|i| {
+                let scheme = if i % 2 == 0 { ChannelIdScheme::Sequential } else { ChannelIdScheme::InstancePrefixed };
+
+                channel_id_sequence(next_channel_id(scheme))
+            }).collect::<Vec<_>>()
+        })).collect();
+
+        let mut sequences = BTreeSet::new();
+
+        for thread in threads {
+            for sequence in thread.join().unwrap() {
+                assert!(sequences.insert(sequence), "Sequence {} was handed out twice", sequence);
+            }
+        }
+    }
+
+    #[test]
+    fn prefixed_ids_keep_the_sequence_in_the_low_bits() {
+        let first = next_channel_id(ChannelIdScheme::InstancePrefixed);
+        let second = next_channel_id(ChannelIdScheme::InstancePrefixed);
+
+        //Every prefixed id of this process shares the same prefix
+        assert_eq!(first & !sequence_mask(), instance_prefix());
+        assert_eq!(second & !sequence_mask(), instance_prefix());
+        assert!(channel_id_sequence(second) > channel_id_sequence(first));
+
+        assert_ne!(instance_prefix() | sequence_mask(), usize::MAX);
+
+        //Sequential ids have no prefix at all
+        let sequential = next_channel_id(ChannelIdScheme::Sequential);
+
+        assert_eq!(sequential, channel_id_sequence(sequential));
+        assert_eq!(channel_id_sequence(instance_prefix() | 42), 42);
+    }
+}
@@ -1,17 +1,44 @@
+pub mod attributes;
+pub mod id;
+mod outbound;
+
+pub use outbound::OutboundBuf;
+
+use std::collections::VecDeque;
+use std::fs::File;
 use std::io::{ErrorKind, Write};
 use std::net::{SocketAddr};
 use std::os::fd::RawFd;
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::{AtomicBool, Ordering};
-use crate::event_group::EventGroupHandle;
-use crate::util::Stream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::channel::attributes::AttributeMap;
+use crate::channel::outbound::{FileRegion, OutboundQueue};
+use crate::codec::Encoder;
+use crate::error::{Result, RusttyError};
+use crate::event_group::{EventGroupHandle, Registration};
+use crate::event_group::read_size::AdaptiveReadSize;
+use crate::event_group::traffic::{ChannelTraffic, Direction};
+use crate::future::{CloseFuture, one_shot, OneShot, ready, WriteFuture};
+use crate::util::{Stream, LockExt};
+use crate::util::pool::BufferPool;
+
+type CloseListener = Box<dyn FnOnce() + Send>;
 
 pub struct Channel {
     id: usize,
     network: ChannelNetwork,
     owning_event_group: EventGroupHandle,
-    //TODO: Maybe add a pipeline to this?
-    pipeline: Vec<fn(Vec<u8>) -> Vec<u8>>
+    //State attached to this channel by the handlers
+    attributes: AttributeMap,
+    //The rate limits of this channel (and of its event group)
+    traffic: ChannelTraffic,
+    //Whether we read from the socket as soon as there is something to read
+    auto_read: AtomicBool,
+    //How much we read from the socket at a time
+    read_size: AdaptiveReadSize,
+    //The listeners waiting for this channel to be closed.
+    //None once the channel has been closed
+    close_listeners: Mutex<Option<Vec<CloseListener>>>,
 }
 
 pub struct ChannelNetwork {
@@ -29,70 +56,84 @@ pub struct ChannelNetwork {
 
     // Do we have any pending information we want to send
     has_pending_tx: AtomicBool,
+    //How this channel is registered in the poller.
+    //Held while the registration is changed, so concurrent changes are not applied out of order
+    registration: Mutex<Registration>,
     //The pending transmission bytes that were not sent
     //as it could not be done in a non blocking way
-    pending_tx: Mutex<Vec<u8>>
+    pending_tx: Mutex<OutboundQueue>,
+
+    //How many bytes have ever been queued in pending_tx (only changed with the pending_tx lock held)
+    //and how many of those have already been written to the socket.
+    //This is how we know when the writes of write_async are done
+    queued_tx_bytes: AtomicU64,
+    flushed_tx_bytes: AtomicU64,
+    //The futures waiting for the queued bytes to be flushed, along with the amount
+    //of flushed bytes they are waiting for
+    write_waiters: Mutex<VecDeque<(u64, OneShot<std::io::Result<()>>)>>,
 }
 
 /// Non-blocking
 impl Write for Channel {
+
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+/// Non-blocking
+/// Writing only requires a shared reference, so that channels can be written to
+/// through the [Arc] they are handed out in (just like [std::net::TcpStream])
+impl Write for &Channel {
     
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        return match self.network.socket.lock().unwrap().write(buf) {
-            Ok(written) => {
-                Ok(written)
-            }
-            Err(err) => {
-                match err.kind() {
-                    ErrorKind::WouldBlock =>  {
-                        
-                        let previous = {
-                            let mut lock_guard = self.network.pending_tx.lock().unwrap();
-
-                            let previous = self.network.has_pending_tx.swap(true, Ordering::SeqCst);
-
-                            //Could this be done without performing this copy?
-                            //It could if we took ownership of the buffer but that would mean we
-                            //No longer implement Write, which isn't ideal
-                            lock_guard.extend_from_slice(buf);
-
-                            previous
-                        };
-
-                        if !previous {
-                            //If we have already registered that we have the intention to write, then
-                            //We don't want to do it again
-                            self.owning_event_group.register_write_intention(self);
-                        }
-
-                        //Do we like returning Ok() here?
-                        Ok(buf.len())
-                    }
-                    _ => {
-                        Err(err)
-                    }
-                }
+        let mut socket = self.network.socket.lock_safe();
 
-            }
-        };
+        //Queue the remaining bytes while we still hold the socket, so no one can write in between
+        let written = self.write_now(&mut **socket, buf)?;
+
+        if written < buf.len() {
+            //We have to copy the buffer as we don't own it.
+            //Channel::send takes ownership of the buffer instead, which avoids this
+            self.queue_pending_tx(&buf[written..]);
+        }
+
+        //Whatever we couldn't write is written by the event group once the socket is writable
+        Ok(buf.len())
     }
 
+    /// The bytes that could not be written right away are written by the event group
+    /// as soon as the socket allows it, so there is nothing for us to do here.
+    /// Use [Channel::write_async] to know when the bytes have actually been written
     fn flush(&mut self) -> std::io::Result<()> {
-        todo!()
+        Ok(())
     }
 }
 
 impl Channel {
 
-    pub(crate) fn new(id: usize, network: ChannelNetwork, owning_event_group: EventGroupHandle) -> Arc<Self> {
-        Arc::new(Channel {
+    pub(crate) fn new(id: usize, network: ChannelNetwork, owning_event_group: EventGroupHandle) -> Self {
+        let traffic = ChannelTraffic::new(owning_event_group.traffic().clone());
+
+        let read_size = AdaptiveReadSize::new(owning_event_group.read_sizing());
+
+        Channel {
             id,
             network,
             owning_event_group,
-            pipeline: vec![],
-        })
+            traffic,
+            auto_read: AtomicBool::new(true),
+            read_size,
+            attributes: AttributeMap::new(),
+            close_listeners: Mutex::new(Some(Vec::new())),
+        }
     }
 
+    /// The id of this channel, which is unique across every event group of the process
     pub fn id(&self) -> usize {
         self.id
     }
@@ -101,15 +142,265 @@ impl Channel {
         &self.network
     }
 
+    /// The attributes attached to this channel
+    pub fn attributes(&self) -> &AttributeMap {
+        &self.attributes
+    }
+
+    /// Enable or disable reading from this channel.
+    /// While disabled, we stop listening for incoming bytes, so they pile up in the
+    /// socket's receive buffer and TCP flow control makes the peer slow down.
+    /// This allows handlers that are falling behind to apply backpressure
+    pub fn set_auto_read(&self, auto_read: bool) -> Result<()> {
+        if self.auto_read.swap(auto_read, Ordering::SeqCst) != auto_read {
+            self.owning_event_group.rearm(self)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether we are reading from this channel
+    pub fn is_auto_read(&self) -> bool {
+        self.auto_read.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn traffic(&self) -> &ChannelTraffic {
+        &self.traffic
+    }
+
+    pub(crate) fn read_size(&self) -> &AdaptiveReadSize {
+        &self.read_size
+    }
+
+    /// Write to the socket, without exceeding the write rate of this channel.
+    /// If we are not allowed to write anything right now, this behaves as if the socket
+    /// would block, so the bytes get queued and are written by the event group later on
+    fn write_shaped(&self, socket: &mut dyn Stream, buf: &[u8]) -> std::io::Result<usize> {
+        let allowed = match self.traffic.allowance(Direction::Write) {
+            Some(0) if !buf.is_empty() => return Err(std::io::Error::from(ErrorKind::WouldBlock)),
+            Some(allowance) => buf.len().min(allowance as usize),
+            None => buf.len()
+        };
+
+        let written = socket.write(&buf[..allowed])?;
+
+        self.traffic.consume(Direction::Write, written);
+
+        Ok(written)
+    }
+
+    /// Encode the message with the given encoder and write it to this channel
+    pub fn write_encoded<T, E>(&self, encoder: &mut E, item: T) -> std::io::Result<()>
+        where E: Encoder<T> {
+        let mut buf = Vec::new();
+
+        encoder.encode(item, &mut buf)?;
+
+        (&*self).write_all(&buf)
+    }
+
+    /// Write as much of the buffer as we can right now, while holding the socket.
+    /// Returns how many bytes were written, stopping early when the socket would block
+    /// or when there are bytes queued before ours, so the rest has to be queued
+    fn write_now(&self, socket: &mut dyn Stream, buf: &[u8]) -> std::io::Result<usize> {
+        if self.is_closed() {
+            //Nothing would ever write the bytes we queue
+            return Err(RusttyError::ChannelClosed.into());
+        }
+
+        let mut written = 0;
+
+        loop {
+            if written == buf.len() || self.network.has_pending_tx.load(Ordering::SeqCst) {
+                //There are bytes waiting to be written before ours, so we have to queue
+                //the rest of the buffer to maintain the ordering
+                return Ok(written);
+            }
+
+            match self.write_shaped(socket, &buf[written..]) {
+                Ok(0) => return Err(std::io::Error::from(ErrorKind::WriteZero)),
+                Ok(bytes_written) => written += bytes_written,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(written),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err)
+            }
+        }
+    }
+
+    /// Write the entire buffer to this channel.
+    /// The returned future completes once all of it has actually been written to the socket,
+    /// not just queued to be written when the socket becomes writable.
+    pub fn write_async(&self, buf: &[u8]) -> WriteFuture {
+        let result = {
+            let mut socket = self.network.socket.lock_safe();
+
+            //Queue the remaining bytes while we still hold the socket, so no one can write in between
+            self.write_now(&mut **socket, buf)
+                .map(|written| (written < buf.len()).then(|| self.queue_pending_tx(&buf[written..])))
+        };
+
+        match result {
+            Ok(None) => ready(Ok(())),
+            Ok(Some(flushed_target)) => self.network.wait_for_flush(flushed_target),
+            Err(err) => ready(Err(err))
+        }
+    }
+
+    /// Write an owned buffer to this channel.
+    /// Whatever can't be written right away is queued without being copied, so a shared
+    /// buffer (like an [Arc<\[u8\]>]) can be sent to any amount of channels while only
+    /// existing once in memory.
+    /// Like [Write::write_all], this only fails if the socket has failed
+    pub fn send<B>(&self, buf: B) -> std::io::Result<()> where B: Into<OutboundBuf> {
+        self.send_now(buf.into()).map(|_| ())
+    }
+
+    /// Write an owned buffer to this channel, without copying it, like [Channel::send].
+    /// The returned future completes once all of it has actually been written to the socket
+    pub fn send_async<B>(&self, buf: B) -> WriteFuture where B: Into<OutboundBuf> {
+        match self.send_now(buf.into()) {
+            Ok(None) => ready(Ok(())),
+            Ok(Some(flushed_target)) => self.network.wait_for_flush(flushed_target),
+            Err(err) => ready(Err(err))
+        }
+    }
+
+    /// Write as much of the buffer as we can right now and queue the rest.
+    /// Returns the amount of flushed bytes after which the queued part will have been written, if any
+    fn send_now(&self, buf: OutboundBuf) -> std::io::Result<Option<u64>> {
+        let mut socket = self.network.socket.lock_safe();
+
+        let written = self.write_now(&mut **socket, buf.as_slice())?;
+
+        Ok((written < buf.len()).then(|| self.queue_pending(|network| network.append_pending_buf(buf, written))))
+    }
+
+    /// Write `len` bytes of the file, starting at `offset`, to this channel.
+    /// The region is queued behind anything that is already waiting to be written and
+    /// is sent a piece at a time as the socket becomes writable, so files of any size can
+    /// be sent without loading them into memory.
+    /// On plain TCP and Unix sockets the kernel copies the bytes straight from the file (with
+    /// sendfile), on any other stream we read them and write them ourselves.
+    /// The returned future completes once the whole region has been written
+    pub fn send_file(&self, file: File, offset: u64, len: u64) -> WriteFuture {
+        if len == 0 {
+            return ready(Ok(()));
+        }
+
+        if self.is_closed() {
+            return ready(Err(RusttyError::ChannelClosed.into()));
+        }
+
+        let flushed_target = {
+            //Hold the socket, so no one can write in between the bytes queued before us and the file
+            let _socket = self.network.socket.lock_safe();
+
+            self.queue_pending(|network| network.append_pending_file(FileRegion::new(file, offset, len)))
+        };
+
+        self.network.wait_for_flush(flushed_target)
+    }
+
+    /// The amount of bytes that have been queued to be written, but are still waiting
+    /// for the socket to become writable.
+    /// This is a good indication of how far behind the peer is in reading what we send it
+    pub fn pending_write_bytes(&self) -> u64 {
+        self.network.pending_tx_bytes()
+    }
+
+    /// Give a buffer received by [ChannelHandler::handle_message_received] back to the
+    /// buffer pool of the event group, once its contents are no longer needed,
+    /// so the next reads can reuse it instead of allocating a new one
+    pub fn release_buffer(&self, buf: Vec<u8>) {
+        self.owning_event_group.buffer_pool().release(buf)
+    }
+
+    /// Get a future that completes once this channel has been closed
+    pub fn close_future(&self) -> CloseFuture {
+        let (completion, future) = one_shot();
+
+        self.on_close(move || completion.complete(()));
+
+        future
+    }
+
+    /// Register a listener to be called once this channel has been closed.
+    /// If the channel is already closed, the listener is called right away
+    pub fn on_close<F>(&self, listener: F) where F: FnOnce() + Send + 'static {
+        let mut close_listeners = self.close_listeners.lock_safe();
+
+        match &mut *close_listeners {
+            Some(listeners) => listeners.push(Box::new(listener)),
+            None => {
+                drop(close_listeners);
+
+                listener()
+            }
+        }
+    }
+
+    /// Whether this channel has been closed
+    pub fn is_closed(&self) -> bool {
+        self.close_listeners.lock_safe().is_none()
+    }
+
+    /// Queue bytes to be written once the socket becomes writable.
+    /// Returns the amount of flushed bytes after which these bytes will have been written
+    fn queue_pending_tx(&self, buf: &[u8]) -> u64 {
+        self.queue_pending(|network| network.append_pending_tx(buf, self.owning_event_group.buffer_pool()))
+    }
+
+    fn queue_pending<F>(&self, append: F) -> u64 where F: FnOnce(&ChannelNetwork) -> (bool, u64) {
+        let (previous, flushed_target) = append(&self.network);
+
+        if !previous {
+            //If we have already registered that we have the intention to write, then
+            //We don't want to do it again
+            //If the event group is gone the channel is closed, which fails anyone waiting on these bytes
+            let _ = self.owning_event_group.register_write_intention(self);
+        }
+
+        flushed_target
+    }
+
+    /// Notify everyone that is waiting on this channel that it has been closed
+    pub(crate) fn notify_closed(&self) {
+        let close_listeners = self.close_listeners.lock_safe().take();
+
+        for listener in close_listeners.into_iter().flatten() {
+            listener();
+        }
+
+        self.network.fail_write_waiters(ErrorKind::NotConnected);
+    }
+
     /// Close this channel.
     /// This removes the channel from its event group, meaning it will no longer be able
     /// to receive messages.
     /// The underlying socket is also closed
     pub fn close(self: &Arc<Channel>) {
-        self.owning_event_group.close_connection(self)
+        self.close_with(None)
+    }
+
+    /// Close this channel, letting the handler know about the error that caused it
+    pub(crate) fn close_with(self: &Arc<Channel>, err: Option<RusttyError>) {
+        if self.owning_event_group.close_connection(self, err).is_err() {
+            //The event group is no longer running, so there's nothing to remove the channel from
+            self.notify_closed();
+        }
     }
 }
 
+/// The outcome of writing the pending bytes of a channel
+pub(crate) struct Flushed {
+    /// How many bytes were written
+    pub(crate) written: usize,
+    /// Whether we stopped because the socket would block
+    pub(crate) would_block: bool,
+    /// The error that stopped us, if any
+    pub(crate) error: Option<std::io::Error>,
+}
+
 impl ChannelNetwork {
 
     pub fn new(addr: SocketAddr, socket: Box<dyn Stream>, pending_tx_size: usize) -> Self {
@@ -118,7 +409,11 @@ impl ChannelNetwork {
             addr,
             socket: Mutex::new(socket),
             has_pending_tx: AtomicBool::new(false),
-            pending_tx: Mutex::new(Vec::with_capacity(pending_tx_size)),
+            registration: Mutex::new(Registration::default()),
+            pending_tx: Mutex::new(OutboundQueue::new(pending_tx_size)),
+            queued_tx_bytes: AtomicU64::new(0),
+            flushed_tx_bytes: AtomicU64::new(0),
+            write_waiters: Mutex::new(VecDeque::new()),
         }
     }
 
@@ -135,55 +430,268 @@ impl ChannelNetwork {
         self.raw_fd
     }
 
-    ///We want to extends the mutex with the remaining bytes from the previous write
-    /// They must be added to the beginning of the vector so we maintain the original
-    /// ordering of the application.
-    pub(crate) fn begin_extend_from_slice(&self, slice: &[u8]) {
-        let mut lock_guard = self.pending_tx.lock().unwrap();
+    pub(crate) fn registration(&self) -> &Mutex<Registration> {
+        &self.registration
+    }
 
-        self.has_pending_tx.store(true, Ordering::SeqCst);
+    /// Append bytes to the end of the pending tx buffer.
+    /// Returns whether there were already pending bytes and the amount of flushed bytes
+    /// after which these will have been written
+    pub(crate) fn append_pending_tx(&self, buf: &[u8], pool: &BufferPool) -> (bool, u64) {
+        self.append_pending(buf.len() as u64, |pending_tx| pending_tx.push_slice(buf, pool))
+    }
 
-        if lock_guard.is_empty() {
-            lock_guard.extend_from_slice(slice);
-        } else {
+    /// Append an owned buffer to the end of the pending tx buffer, without copying it.
+    /// The first `offset` bytes of the buffer have already been written
+    pub(crate) fn append_pending_buf(&self, buf: OutboundBuf, offset: usize) -> (bool, u64) {
+        self.append_pending((buf.len() - offset) as u64, |pending_tx| pending_tx.push(buf, offset))
+    }
 
-            //This is so the elements that were left from previous send attempts are put at the start of the
-            //Vector, instead of at the end, so they are the first to be sent through the Stream (or else
-            // We could fracture various packets, etc) This is important for maintaining sequential sending
+    /// Append a region of a file to the end of the pending tx buffer
+    pub(crate) fn append_pending_file(&self, region: FileRegion) -> (bool, u64) {
+        self.append_pending(region.remaining(), |pending_tx| pending_tx.push_file(region))
+    }
 
-            //Maybe optimize this?
-            //It can actually
-            let mut final_vec = Vec::with_capacity(lock_guard.len() + slice.len());
+    fn append_pending<F>(&self, len: u64, push: F) -> (bool, u64) where F: FnOnce(&mut OutboundQueue) {
+        let mut lock_guard = self.pending_tx.lock_safe();
 
-            final_vec.extend_from_slice(slice);
-            final_vec.extend_from_slice(&lock_guard[..]);
+        let previous = self.has_pending_tx.swap(true, Ordering::SeqCst);
 
-            let _prev = std::mem::replace(&mut *lock_guard, final_vec);
-        }
+        push(&mut lock_guard);
+
+        let queued = self.queued_tx_bytes.fetch_add(len, Ordering::SeqCst) + len;
+
+        (previous, queued)
     }
-    
-    pub(crate) fn take_pending_tx(&self) -> Option<Vec<u8>> {
-        if self.has_pending_tx.load(Ordering::Relaxed) {
-            let new_vec = Vec::with_capacity(1024);
-
-            let mut lock_guard = self.pending_tx.lock().unwrap();
-
-            //This must be done after the pending_tx lock so that no one can try to get in between us setting the
-            //Atomic variable to false and taking the vector, as that can cause issues
-            if let Ok(_) = self.has_pending_tx.compare_exchange(true, false,
-                                                    Ordering::SeqCst, Ordering::SeqCst) {
-                Some(std::mem::replace(&mut lock_guard, new_vec))
-            } else {
-                None
+
+    /// Register that some of the pending bytes have been written to the socket,
+    /// completing the write futures that were waiting for them
+    pub(crate) fn report_flushed(&self, written: usize) {
+        if written == 0 {
+            return;
+        }
+
+        let flushed = self.flushed_tx_bytes.fetch_add(written as u64, Ordering::SeqCst) + written as u64;
+
+        let mut completed = Vec::new();
+
+        {
+            let mut write_waiters = self.write_waiters.lock_safe();
+
+            while let Some((target, _)) = write_waiters.front() {
+                if *target > flushed {
+                    break;
+                }
+
+                completed.extend(write_waiters.pop_front().map(|(_, waiter)| waiter));
             }
-        } else {
-            None
         }
 
+        for waiter in completed {
+            waiter.complete(Ok(()));
+        }
+    }
+
+    pub(crate) fn pending_tx_bytes(&self) -> u64 {
+        let flushed = self.flushed_tx_bytes.load(Ordering::SeqCst);
+
+        self.queued_tx_bytes.load(Ordering::SeqCst).saturating_sub(flushed)
     }
 
+    /// Get a future that completes once the given amount of bytes has been flushed
+    fn wait_for_flush(&self, flushed_target: u64) -> WriteFuture {
+        let mut write_waiters = self.write_waiters.lock_safe();
+
+        if self.flushed_tx_bytes.load(Ordering::SeqCst) >= flushed_target {
+            return ready(Ok(()));
+        }
+
+        let (completion, future) = one_shot();
+
+        write_waiters.push_back((flushed_target, completion));
+
+        future
+    }
+
+    /// Fail all of the write futures that are still waiting
+    pub(crate) fn fail_write_waiters(&self, kind: ErrorKind) {
+        let waiters: Vec<_> = self.write_waiters.lock_safe().drain(..).collect();
+
+        for (_, waiter) in waiters {
+            waiter.complete(Err(std::io::Error::from(kind)));
+        }
+    }
+
+    /// Write as many of the pending bytes as the socket takes, with at most `max_writes`
+    /// writes and up to `limit` bytes.
+    /// The queued segments are written with vectored writes, so they are never copied
+    pub(crate) fn write_pending_tx(&self, limit: usize, max_writes: usize, pool: &BufferPool) -> Flushed {
+        //Same order as write_async, which queues its bytes while holding the socket
+        let mut socket = self.socket.lock_safe();
+
+        let mut pending_tx = self.pending_tx.lock_safe();
+
+        let mut written = 0;
+        let mut writes = 0;
+        let mut would_block = false;
+
+        let error = loop {
+            if pending_tx.is_empty() || written >= limit || writes >= max_writes {
+                break None;
+            }
+
+            writes += 1;
+
+            match pending_tx.write_to(&mut **socket, limit - written, pool) {
+                Ok(0) => break Some(std::io::Error::from(ErrorKind::WriteZero)),
+                Ok(bytes_written) => {
+                    //Partial writes just move us further into the segments
+                    pending_tx.advance(bytes_written, pool);
+
+                    written += bytes_written;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    would_block = true;
+
+                    break None;
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => break Some(err)
+            }
+        };
+
+        //Still under the pending_tx lock, so no one can queue bytes in between
+        self.has_pending_tx.store(!pending_tx.is_empty(), Ordering::SeqCst);
+
+        Flushed {
+            written,
+            would_block,
+            error,
+        }
+    }
 
     pub fn has_pending_tx(&self) -> &AtomicBool {
         &self.has_pending_tx
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use crate::config::BaseConfig;
+    use crate::error::RusttyError;
+    use crate::event_group::EventGroup;
+    use crate::util::ChannelHandler;
+    use super::*;
+
+    struct IgnoringHandler;
+
+    impl ChannelHandler for IgnoringHandler {
+        fn handle_connection_established(&self, channel: Channel) -> Channel {
+            channel
+        }
+
+        fn handle_message_received(&self, _channel: Arc<Channel>, _buf: Vec<u8>) {}
+
+        fn handle_connection_removed(&self, _channel: Arc<Channel>, _err: Option<RusttyError>) {}
+    }
+
+    #[test]
+    fn writes_are_queued_behind_pending_bytes() {
+        let handle = EventGroup::initialize_event_group(0, &BaseConfig::new(1, 1024), Arc::new(IgnoringHandler)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let (conn, addr) = listener.accept().unwrap();
+        conn.set_nonblocking(true).unwrap();
+
+        //Never registered, so the queued bytes are only written once we read them here
+        let channel = Channel::new(1, ChannelNetwork::new(addr, Box::new(conn), 1024), handle);
+
+        let payload = vec![1; 8 * 1024 * 1024];
+
+        (&channel).write_all(&payload).unwrap();
+
+        let pending = channel.pending_write_bytes();
+        assert!(pending > 0);
+
+        //Make room in the socket, the next bytes still have to wait for the queued ones
+        let mut received = vec![0; 64 * 1024];
+        client.read_exact(&mut received).unwrap();
+
+        (&channel).write_all(b"end").unwrap();
+
+        assert_eq!(channel.pending_write_bytes(), pending + 3);
+    }
+
+    #[test]
+    fn closed_channels_refuse_writes() {
+        let handle = EventGroup::initialize_event_group(0, &BaseConfig::new(1, 1024), Arc::new(IgnoringHandler)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let (conn, addr) = listener.accept().unwrap();
+        conn.set_nonblocking(true).unwrap();
+
+        let channel = Channel::new(1, ChannelNetwork::new(addr, Box::new(conn), 1024), handle);
+
+        channel.notify_closed();
+
+        assert!(channel.is_closed());
+        assert_eq!((&channel).write(b"ping").unwrap_err().kind(), ErrorKind::NotConnected);
+        assert_eq!(channel.send(b"ping".to_vec()).unwrap_err().kind(), ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn write_futures_complete_once_flushed() {
+        let handle = EventGroup::initialize_event_group(0, &BaseConfig::new(1, 1024), Arc::new(IgnoringHandler)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let (conn, addr) = listener.accept().unwrap();
+        conn.set_nonblocking(true).unwrap();
+
+        let channel = Channel::new(1, ChannelNetwork::new(addr, Box::new(conn), 1024), handle.clone());
+        let close_future = channel.close_future();
+
+        let channel = handle.register_new_connection(channel).unwrap();
+
+        //Too much for the socket to take at once, so most of it is queued
+        let payload = vec![7; 8 * 1024 * 1024];
+
+        let first = channel.write_async(&payload);
+        let second = channel.write_async(b"end");
+
+        assert!(channel.pending_write_bytes() > 0);
+
+        let reader = std::thread::spawn(move || {
+            let mut received = vec![0; payload.len() + 3];
+
+            client.read_exact(&mut received).unwrap();
+
+            //Keep the connection open until we close it ourselves
+            (received[payload.len()..].to_vec(), client)
+        });
+
+        crate::future::block_on(first).unwrap();
+        crate::future::block_on(second).unwrap();
+
+        assert_eq!(channel.pending_write_bytes(), 0);
+
+        let (end, _client) = reader.join().unwrap();
+        assert_eq!(end, b"end");
+
+        assert!(!channel.is_closed());
+
+        channel.close();
+
+        crate::future::block_on(close_future);
+
+        assert!(channel.is_closed());
+        assert!(crate::future::block_on(channel.write_async(b"closed")).is_err());
+    }
+}
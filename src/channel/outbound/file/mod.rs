@@ -0,0 +1,166 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::FileExt;
+use crate::util::Stream;
+use crate::util::pool::BufferPool;
+
+/// The most bytes we hand to a single sendfile call
+const MAX_SENDFILE_CHUNK: u64 = 1 << 30;
+/// The size of the buffer used to copy files to streams that can't use sendfile
+const COPY_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// A region of a file that is waiting to be written to a channel
+pub(crate) struct FileRegion {
+    file: File,
+    //The position in the file of the next byte to write
+    offset: u64,
+    //How many bytes of the region are still to be written
+    remaining: u64,
+}
+
+impl FileRegion {
+    pub(crate) fn new(file: File, offset: u64, len: u64) -> Self {
+        FileRegion {
+            file,
+            offset,
+            remaining: len,
+        }
+    }
+
+    pub(crate) fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Register that bytes of this region have been written
+    pub(crate) fn advance(&mut self, written: u64) {
+        self.offset += written;
+        self.remaining -= written;
+    }
+
+    /// Write up to `limit` bytes of this region to the socket.
+    /// Doesn't advance the region, just like a regular write doesn't advance the buffer
+    pub(crate) fn write_to(&self, socket: &mut dyn Stream, limit: u64, pool: &BufferPool) -> std::io::Result<usize> {
+        let count = self.remaining.min(limit);
+
+        if socket.is_raw_socket() {
+            #[cfg(target_os = "linux")]
+            return self.send_file(socket, count.min(MAX_SENDFILE_CHUNK));
+        }
+
+        self.copy_to(socket, count.min(COPY_CHUNK_SIZE), pool)
+    }
+
+    /// Let the kernel copy the bytes from the file to the socket
+    #[cfg(target_os = "linux")]
+    fn send_file(&self, socket: &mut dyn Stream, count: u64) -> std::io::Result<usize> {
+        use std::os::fd::AsRawFd;
+
+        let mut offset = self.offset as libc::off_t;
+
+        //Sendfile updates the offset we give it, instead of the position of the file,
+        //so the file can be shared with other readers
+        let sent = unsafe {
+            libc::sendfile(socket.as_raw_fd(), self.file.as_raw_fd(), &mut offset, count as usize)
+        };
+
+        match sent {
+            -1 => Err(Error::last_os_error()),
+            //The region goes beyond the end of the file
+            0 => Err(Error::new(ErrorKind::UnexpectedEof, "The file is shorter than the region being sent")),
+            sent => Ok(sent as usize)
+        }
+    }
+
+    /// Read the bytes from the file and write them to the socket ourselves.
+    /// If the socket doesn't take all of them, the rest is read again on the next write
+    fn copy_to(&self, socket: &mut dyn Stream, count: u64, pool: &BufferPool) -> std::io::Result<usize> {
+        let mut buf = pool.lease(count as usize);
+
+        buf.resize(count as usize, 0);
+
+        let result = match self.file.read_at(&mut buf, self.offset) {
+            Ok(0) => Err(Error::new(ErrorKind::UnexpectedEof, "The file is shorter than the region being sent")),
+            Ok(read) => socket.write(&buf[..read]),
+            Err(err) => Err(err)
+        };
+
+        pool.release(buf);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::os::fd::{AsRawFd, RawFd};
+    use std::os::unix::net::UnixStream;
+    use super::*;
+
+    //Wraps a socket, like TLS would, so sendfile can't be used
+    struct Wrapped(UnixStream);
+
+    impl Read for Wrapped {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Wrapped {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl AsRawFd for Wrapped {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0.as_raw_fd()
+        }
+    }
+
+    impl Stream for Wrapped {}
+
+    fn transfer(socket: &mut dyn Stream, peer: &mut UnixStream, pool: &BufferPool) -> Vec<u8> {
+        let path = std::env::temp_dir().join(format!("rustty-file-region-{}", crate::util::random_u64()));
+
+        std::fs::write(&path, b"0123456789").unwrap();
+
+        let mut region = FileRegion::new(File::open(&path).unwrap(), 2, 6);
+
+        std::fs::remove_file(&path).unwrap();
+
+        //Partial writes resume where they stopped
+        let written = region.write_to(socket, 4, pool).unwrap();
+
+        region.advance(written as u64);
+
+        while region.remaining() > 0 {
+            let written = region.write_to(socket, u64::MAX, pool).unwrap();
+
+            region.advance(written as u64);
+        }
+
+        let mut received = vec![0; 6];
+
+        peer.read_exact(&mut received).unwrap();
+
+        received
+    }
+
+    #[test]
+    fn regions_are_sent_with_and_without_sendfile() {
+        let pool = BufferPool::new(1);
+
+        let (mut socket, mut peer) = UnixStream::pair().unwrap();
+
+        assert_eq!(transfer(&mut socket, &mut peer, &pool), b"234567");
+
+        let (socket, mut peer) = UnixStream::pair().unwrap();
+
+        assert_eq!(transfer(&mut Wrapped(socket), &mut peer, &pool), b"234567");
+    }
+}
@@ -0,0 +1,324 @@
+mod file;
+
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter};
+use std::io::IoSlice;
+use std::sync::Arc;
+use crate::util::Stream;
+use crate::util::pool::BufferPool;
+
+pub(crate) use file::FileRegion;
+
+/// The maximum amount of segments we hand to a single vectored write
+const MAX_IO_SLICES: usize = 64;
+
+/// A buffer handed over to a channel to be written, without being copied.
+/// Shared buffers ([Arc<\[u8\]>] or any other shared type through [OutboundBuf::from_shared])
+/// can be cloned cheaply, so the same payload can be queued on many channels
+pub struct OutboundBuf {
+    inner: BufInner,
+}
+
+enum BufInner {
+    Owned(Vec<u8>),
+    Shared(Arc<[u8]>),
+    Static(&'static [u8]),
+    Custom(Box<dyn AsRef<[u8]> + Send>),
+}
+
+/// A segment of the outbound queue
+enum Segment {
+    //A buffer, along with the offset of its first byte that is still to be written
+    Buf(OutboundBuf, usize),
+    //A region of a file, which is written straight from the file
+    File(FileRegion),
+}
+
+/// The bytes waiting to be written to a channel, as a queue of segments.
+/// Segments are written with vectored writes, so they never have to be joined into
+/// a single buffer, and partially written segments are never moved or copied.
+/// Small writes are coalesced into the last segment, so we don't end up with lots of tiny ones
+pub(crate) struct OutboundQueue {
+    segments: VecDeque<Segment>,
+    //The amount of bytes still to be written, across all segments
+    len: u64,
+    //The capacity of the segments we allocate to coalesce small writes into
+    segment_size: usize,
+}
+
+impl OutboundBuf {
+    /// Wrap any other buffer type, such as a reference counted buffer from another crate
+    pub fn from_shared<T>(buf: T) -> Self where T: AsRef<[u8]> + Send + 'static {
+        OutboundBuf { inner: BufInner::Custom(Box::new(buf)) }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match &self.inner {
+            BufInner::Owned(buf) => buf,
+            BufInner::Shared(buf) => buf,
+            BufInner::Static(buf) => buf,
+            BufInner::Custom(buf) => (**buf).as_ref(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.as_slice().is_empty()
+    }
+
+    /// Give the buffer back to the pool, if we own it
+    fn release(self, pool: &BufferPool) {
+        if let BufInner::Owned(buf) = self.inner {
+            pool.release(buf);
+        }
+    }
+
+    /// The owned vector, if we can append to it without reallocating
+    fn spare_vec(&mut self, additional: usize) -> Option<&mut Vec<u8>> {
+        match &mut self.inner {
+            BufInner::Owned(buf) if buf.capacity() - buf.len() >= additional => Some(buf),
+            _ => None
+        }
+    }
+}
+
+impl AsRef<[u8]> for OutboundBuf {
+    fn as_ref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl Debug for OutboundBuf {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OutboundBuf").field("len", &self.len()).finish()
+    }
+}
+
+impl From<Vec<u8>> for OutboundBuf {
+    fn from(buf: Vec<u8>) -> Self {
+        OutboundBuf { inner: BufInner::Owned(buf) }
+    }
+}
+
+impl From<Box<[u8]>> for OutboundBuf {
+    fn from(buf: Box<[u8]>) -> Self {
+        Vec::from(buf).into()
+    }
+}
+
+impl From<String> for OutboundBuf {
+    fn from(buf: String) -> Self {
+        buf.into_bytes().into()
+    }
+}
+
+impl From<Arc<[u8]>> for OutboundBuf {
+    fn from(buf: Arc<[u8]>) -> Self {
+        OutboundBuf { inner: BufInner::Shared(buf) }
+    }
+}
+
+impl From<&'static [u8]> for OutboundBuf {
+    fn from(buf: &'static [u8]) -> Self {
+        OutboundBuf { inner: BufInner::Static(buf) }
+    }
+}
+
+impl From<&'static str> for OutboundBuf {
+    fn from(buf: &'static str) -> Self {
+        buf.as_bytes().into()
+    }
+}
+
+impl OutboundQueue {
+    pub(crate) fn new(segment_size: usize) -> Self {
+        OutboundQueue {
+            segments: VecDeque::new(),
+            len: 0,
+            segment_size,
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Queue a copy of the given bytes
+    pub(crate) fn push_slice(&mut self, buf: &[u8], pool: &BufferPool) {
+        if buf.is_empty() {
+            return;
+        }
+
+        //Only coalesce into the last segment if it has room to spare, so we never reallocate it.
+        //The first segment may be partially written, which is fine as we only append to it
+        let last = match self.segments.back_mut() {
+            Some(Segment::Buf(last, _)) => last.spare_vec(buf.len()),
+            _ => None
+        };
+
+        if let Some(last) = last {
+            last.extend_from_slice(buf);
+
+            self.len += buf.len() as u64;
+
+            return;
+        }
+
+        let mut segment = pool.lease(self.segment_size.max(buf.len()));
+
+        segment.extend_from_slice(buf);
+
+        self.push(segment.into(), 0);
+    }
+
+    /// Queue a buffer without copying it, skipping the first `offset` bytes,
+    /// which have already been written
+    pub(crate) fn push(&mut self, segment: OutboundBuf, offset: usize) {
+        if segment.len() <= offset {
+            return;
+        }
+
+        self.len += (segment.len() - offset) as u64;
+
+        self.segments.push_back(Segment::Buf(segment, offset));
+    }
+
+    /// Queue a region of a file
+    pub(crate) fn push_file(&mut self, region: FileRegion) {
+        if region.remaining() == 0 {
+            return;
+        }
+
+        self.len += region.remaining();
+
+        self.segments.push_back(Segment::File(region));
+    }
+
+    /// Write up to `limit` bytes from the front of the queue to the socket.
+    /// Buffers are written together with a single vectored write, while files
+    /// are written on their own. Doesn't advance the queue
+    pub(crate) fn write_to(&self, socket: &mut dyn Stream, limit: usize, pool: &BufferPool) -> std::io::Result<usize> {
+        match self.segments.front() {
+            Some(Segment::File(region)) => region.write_to(socket, limit as u64, pool),
+            _ => socket.write_vectored(&self.io_slices(limit))
+        }
+    }
+
+    /// The slices to hand to a vectored write, covering at most `limit` bytes.
+    /// Stops at the first file in the queue
+    fn io_slices(&self, limit: usize) -> Vec<IoSlice<'_>> {
+        let mut slices = Vec::with_capacity(self.segments.len().min(MAX_IO_SLICES));
+
+        let mut remaining = limit;
+
+        for segment in self.segments.iter().take(MAX_IO_SLICES) {
+            let Segment::Buf(segment, offset) = segment else {
+                break;
+            };
+
+            if remaining == 0 {
+                break;
+            }
+
+            let segment = &segment.as_slice()[*offset..];
+
+            let segment = &segment[..segment.len().min(remaining)];
+
+            remaining -= segment.len();
+
+            slices.push(IoSlice::new(segment));
+        }
+
+        slices
+    }
+
+    /// Drop the given amount of bytes from the front of the queue, after they have been written
+    /// Fully written buffers are given back to the pool
+    pub(crate) fn advance(&mut self, written: usize, pool: &BufferPool) {
+        let mut written = written as u64;
+
+        self.len -= written;
+
+        while written > 0 {
+            let front_len = match self.segments.front() {
+                Some(Segment::Buf(front, offset)) => (front.len() - offset) as u64,
+                Some(Segment::File(region)) => region.remaining(),
+                None => break
+            };
+
+            if written < front_len {
+                match self.segments.front_mut() {
+                    Some(Segment::Buf(_, offset)) => *offset += written as usize,
+                    Some(Segment::File(region)) => region.advance(written),
+                    None => {}
+                }
+
+                break;
+            }
+
+            written -= front_len;
+
+            if let Some(Segment::Buf(front, _)) = self.segments.pop_front() {
+                front.release(pool);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flatten(queue: &OutboundQueue, limit: usize) -> Vec<u8> {
+        queue.io_slices(limit).iter().flat_map(|slice| slice.iter().copied()).collect()
+    }
+
+    #[test]
+    fn partial_writes_advance_through_segments() {
+        let pool = BufferPool::new(1);
+
+        let mut queue = OutboundQueue::new(4);
+
+        queue.push_slice(b"ab", &pool);
+        queue.push_slice(b"cd", &pool);
+        queue.push(b"efg".to_vec().into(), 0);
+        //Owned buffers are only appended to if they have room to spare
+        queue.push_slice(b"hijk", &pool);
+
+        assert_eq!(queue.io_slices(usize::MAX).len(), 3);
+        assert_eq!(flatten(&queue, usize::MAX), b"abcdefghijk");
+        assert_eq!(flatten(&queue, 5), b"abcde");
+
+        queue.advance(3, &pool);
+
+        assert_eq!(flatten(&queue, usize::MAX), b"defghijk");
+
+        queue.advance(6, &pool);
+
+        assert_eq!(flatten(&queue, usize::MAX), b"jk");
+
+        queue.advance(2, &pool);
+
+        assert!(queue.is_empty());
+        assert!(queue.io_slices(usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn shared_buffers_are_not_copied() {
+        let payload: Arc<[u8]> = Arc::from(&b"payload"[..]);
+
+        let pool = BufferPool::new(1);
+
+        let mut queue = OutboundQueue::new(16);
+
+        queue.push(payload.clone().into(), 3);
+        //Never coalesced into a shared segment
+        queue.push_slice(b"!", &pool);
+
+        assert_eq!(flatten(&queue, usize::MAX), b"load!");
+        assert_eq!(queue.io_slices(usize::MAX)[0].as_ptr(), payload[3..].as_ptr());
+    }
+}
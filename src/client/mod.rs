@@ -0,0 +1,156 @@
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use crossbeam_channel::Sender;
+use log::debug;
+use crate::channel::{Channel, ChannelNetwork};
+use crate::channel::id::next_channel_id;
+use crate::config::BaseConfig;
+use crate::error::{Result, RusttyError};
+use crate::event_group::{EventGroup, EventGroupHandle};
+use crate::future::{ConnectFuture, one_shot};
+use crate::util::ChannelHandler;
+use crate::server::OverloadStats;
+use crate::util::pool::PoolStats;
+
+/// How many connections a connector can be establishing at the same time.
+/// Any other connection waits for one of these to be done
+const CONNECTOR_THREADS: usize = 4;
+
+/// A connection to establish, run by one of the connector threads
+type ConnectJob = Box<dyn FnOnce() + Send>;
+
+/// Establishes outgoing connections, registering them as channels in its event group
+pub struct Connector {
+    config: BaseConfig,
+    event_group: EventGroupHandle,
+    //The queue of the connector threads, which stop once the connector is dropped
+    jobs: Sender<ConnectJob>,
+}
+
+impl Connector {
+    /// Create a connector whose connections are handled by the given handler
+    pub fn new<C>(config: BaseConfig, handler: C) -> Result<Self> where C: ChannelHandler + 'static {
+        let event_group = EventGroup::initialize_event_group(0, &config, Arc::new(handler))?;
+
+        let (jobs, job_rx) = crossbeam_channel::unbounded::<ConnectJob>();
+
+        //The standard library has no way to perform non blocking connects,
+        //so we connect from separate threads to not block the callers
+        for thread_id in 0..CONNECTOR_THREADS {
+            let job_rx = job_rx.clone();
+
+            std::thread::Builder::new()
+                .name(format!("Connector thread #{}", thread_id))
+                .spawn(move || {
+                    for job in job_rx {
+                        job();
+                    }
+
+                    debug!("Stopping connector thread #{}", thread_id);
+                })?;
+        }
+
+        Ok(Connector {
+            config,
+            event_group,
+            jobs,
+        })
+    }
+
+    pub fn config(&self) -> &BaseConfig {
+        &self.config
+    }
+
+    /// How often the read and write buffers of the connections were reused
+    pub fn buffer_pool_stats(&self) -> PoolStats {
+        self.event_group.buffer_pool().stats()
+    }
+
+    /// How many events and connections were dropped because the event group was overloaded
+    pub fn overload_stats(&self) -> OverloadStats {
+        self.event_group.overload_stats()
+    }
+
+    /// Connect to the given address.
+    /// The returned future resolves to the channel once the connection is established and
+    /// the channel has been registered in the event group.
+    pub fn connect<A>(&self, addr: A) -> ConnectFuture where A: ToSocketAddrs + Send + 'static {
+        let (completion, future) = one_shot();
+
+        let event_group = self.event_group.clone();
+        let channel_id_scheme = self.config.channel_id_scheme();
+        let pending_tx_size = self.config.pending_tx_base_vec_size();
+
+        let job: ConnectJob = Box::new(move || {
+            let result = establish(addr, pending_tx_size).and_then(|network| {
+                let channel = Channel::new(next_channel_id(channel_id_scheme), network, event_group.clone());
+
+                event_group.register_new_connection(channel)
+            });
+
+            completion.complete(result);
+        });
+
+        match self.jobs.send(job) {
+            Ok(()) => future,
+            //Every connector thread has stopped, which only happens if they panicked
+            Err(_) => crate::future::ready(Err(RusttyError::Registration))
+        }
+    }
+}
+
+fn establish<A>(addr: A, pending_tx_size: usize) -> Result<ChannelNetwork> where A: ToSocketAddrs {
+    let stream = TcpStream::connect(addr)?;
+
+    stream.set_nonblocking(true)?;
+
+    let peer_addr = stream.peer_addr()?;
+
+    Ok(ChannelNetwork::new(peer_addr, Box::new(stream), pending_tx_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use crate::channel::Channel;
+    use crate::future::block_on;
+    use super::*;
+
+    struct IgnoringHandler;
+
+    impl ChannelHandler for IgnoringHandler {
+        fn handle_connection_established(&self, channel: Channel) -> Channel {
+            channel
+        }
+
+        fn handle_message_received(&self, _channel: Arc<Channel>, _buf: Vec<u8>) {}
+
+        fn handle_connection_removed(&self, _channel: Arc<Channel>, _err: Option<RusttyError>) {}
+    }
+
+    #[test]
+    fn connections_are_established_by_the_connector_threads() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let connector = Connector::new(BaseConfig::new(1, 1024), IgnoringHandler).unwrap();
+
+        //More connections than connector threads
+        let futures: Vec<_> = (0..CONNECTOR_THREADS * 2).map(|_| connector.connect(addr)).collect();
+
+        let mut ids = Vec::new();
+
+        for future in futures {
+            let channel = block_on(future).unwrap();
+
+            assert_eq!(channel.network().addr(), addr);
+
+            ids.push(channel.id());
+        }
+
+        ids.sort_unstable();
+        ids.dedup();
+
+        assert_eq!(ids.len(), CONNECTOR_THREADS * 2);
+    }
+}
@@ -0,0 +1,62 @@
+use std::io;
+use std::io::ErrorKind;
+use crate::codec::{Decoder, Encoder};
+
+/// A codec for newline delimited messages (such as JSON lines).
+/// The delimiter (and a preceding carriage return, if any) is not included in the decoded messages
+pub struct LinesCodec {
+    max_line_len: usize,
+}
+
+impl LinesCodec {
+    pub fn new(max_line_len: usize) -> Self {
+        LinesCodec {
+            max_line_len,
+        }
+    }
+
+    pub fn max_line_len(&self) -> usize {
+        self.max_line_len
+    }
+}
+
+impl Decoder for LinesCodec {
+    type Item = Vec<u8>;
+
+    fn decode(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        let line_end = match buf.iter().position(|byte| *byte == b'\n') {
+            Some(line_end) => line_end,
+            None if buf.len() > self.max_line_len => {
+                return Err(io::Error::new(ErrorKind::InvalidData, "Line exceeds the maximum length"));
+            }
+            None => return Ok(None)
+        };
+
+        if line_end > self.max_line_len {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Line exceeds the maximum length"));
+        }
+
+        let mut line: Vec<u8> = buf.drain(..=line_end).collect();
+
+        line.pop();
+
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+
+        Ok(Some(line))
+    }
+}
+
+impl Encoder<Vec<u8>> for LinesCodec {
+    fn encode(&mut self, item: Vec<u8>, dst: &mut Vec<u8>) -> io::Result<()> {
+        if item.len() > self.max_line_len || item.contains(&b'\n') {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "Line is too long or contains a line break"));
+        }
+
+        dst.extend_from_slice(&item);
+        dst.push(b'\n');
+
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+pub mod websocket;
+pub mod resp;
+pub mod varint;
+pub mod lines;
+pub mod typed;
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use log::debug;
+use crate::channel::Channel;
+use crate::channel::attributes::AttributeKey;
+use crate::error::RusttyError;
+use crate::util::{ChannelHandler, LockExt};
+
+/// A decoder turns the raw byte stream received by a channel into messages.
+/// Decoders are stateful and are kept per channel, since a message can be split
+/// across multiple reads.
+pub trait Decoder: Send {
+    /// The type of message produced by this decoder
+    type Item;
+
+    /// Attempt to decode a message from the start of the given buffer.
+    /// If a complete message is available, the bytes it occupied must be removed
+    /// from the buffer and the message returned.
+    /// If there are not enough bytes to produce a message, Ok(None) should be returned
+    /// and the buffer left untouched, so we can try again when more bytes arrive.
+    ///
+    /// Malformed input should be reported with an Error of ErrorKind::InvalidData
+    fn decode(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<Self::Item>>;
+}
+
+/// An encoder turns messages into the bytes that should be written to a channel
+pub trait Encoder<Item>: Send {
+    /// Encode the given message, appending the resulting bytes to dst
+    fn encode(&mut self, item: Item, dst: &mut Vec<u8>) -> io::Result<()>;
+}
+
+/// A handler for the decoded messages of channels
+pub trait MessageHandler<T>: Sync + Send {
+    /// Handle a new connection having just been established
+    fn handle_connection_established(&self, channel: Channel) -> Channel {
+        channel
+    }
+
+    /// Handle a new decoded message being received
+    fn handle_message(&self, channel: &Arc<Channel>, message: T);
+
+    /// Handle the channel having received bytes that could not be decoded.
+    /// The channel is closed afterwards.
+    fn handle_decode_error(&self, _channel: &Arc<Channel>, _err: &io::Error) {}
+
+    /// Handle a connection being removed
+    fn handle_connection_removed(&self, _channel: &Arc<Channel>) {}
+}
+
+/// A [ChannelHandler] that runs the bytes received by each channel through a [Decoder]
+/// and delivers the decoded messages to a [MessageHandler].
+/// A new decoder is created for every channel with the given factory, and kept in the
+/// attributes of the channel.
+pub struct CodecHandler<D, F, H>
+    where D: Decoder + 'static,
+          F: Fn() -> D + Sync + Send,
+          H: MessageHandler<D::Item> {
+    decoder_factory: F,
+    handler: H,
+}
+
+/// The per channel decoding state
+struct DecoderState<D> {
+    decoder: D,
+    buffer: Vec<u8>,
+    failed: bool,
+}
+
+impl<D, F, H> CodecHandler<D, F, H>
+    where D: Decoder + 'static,
+          F: Fn() -> D + Sync + Send,
+          H: MessageHandler<D::Item> {
+    pub fn new(decoder_factory: F, handler: H) -> Self {
+        CodecHandler {
+            decoder_factory,
+            handler,
+        }
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    /// The attribute the decoding state of each channel is kept in
+    fn decoder_key() -> AttributeKey<Mutex<DecoderState<D>>> {
+        AttributeKey::new("rustty.codec.decoder")
+    }
+
+    fn decoder_state(&self, channel: &Channel) -> Arc<Mutex<DecoderState<D>>> {
+        channel.attributes().get_or_insert_with(&Self::decoder_key(), || Mutex::new(DecoderState::new((self.decoder_factory)())))
+    }
+}
+
+impl<D, F, H> ChannelHandler for CodecHandler<D, F, H>
+    where D: Decoder + 'static,
+          F: Fn() -> D + Sync + Send,
+          H: MessageHandler<D::Item> {
+    fn handle_connection_established(&self, channel: Channel) -> Channel {
+        let state = DecoderState::new((self.decoder_factory)());
+
+        channel.attributes().insert(&Self::decoder_key(), Mutex::new(state));
+
+        self.handler.handle_connection_established(channel)
+    }
+
+    fn handle_message_received(&self, channel: Arc<Channel>, buf: Vec<u8>) {
+        let state = self.decoder_state(&channel);
+
+        let mut state = state.lock_safe();
+
+        let DecoderState { decoder, buffer, failed } = &mut *state;
+
+        if *failed {
+            //The channel is already being closed, ignore anything else it sends
+            channel.release_buffer(buf);
+
+            return;
+        }
+
+        if buffer.is_empty() {
+            //Decode straight from the received buffer, giving back the one we were done with
+            channel.release_buffer(std::mem::replace(buffer, buf));
+        } else {
+            buffer.extend_from_slice(&buf);
+
+            channel.release_buffer(buf);
+        }
+
+        loop {
+            match decoder.decode(buffer) {
+                Ok(Some(message)) => {
+                    self.handler.handle_message(&channel, message);
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    debug!("Closing connection {} as we failed to decode its messages: {:?}", channel.id(), err);
+
+                    self.handler.handle_decode_error(&channel, &err);
+
+                    *failed = true;
+
+                    channel.close_with(Some(RusttyError::Protocol(err.to_string())));
+
+                    break;
+                }
+            }
+        }
+    }
+
+    fn handle_connection_removed(&self, channel: Arc<Channel>, _err: Option<RusttyError>) {
+        channel.attributes().remove(&Self::decoder_key());
+
+        self.handler.handle_connection_removed(&channel);
+    }
+}
+
+impl<D> DecoderState<D> {
+    fn new(decoder: D) -> Self {
+        DecoderState {
+            decoder,
+            buffer: Vec::new(),
+            failed: false,
+        }
+    }
+}
@@ -0,0 +1,526 @@
+use std::io;
+use std::io::ErrorKind;
+use crate::codec::{Decoder, Encoder};
+
+/// The largest bulk string we accept by default (the same as Redis' proto-max-bulk-len)
+const DEFAULT_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// How deeply aggregate types (arrays, maps, ...) may be nested
+const MAX_NESTING_DEPTH: usize = 128;
+
+/// The maximum length of a single line (simple strings, errors, lengths, ...).
+/// Without this limit a peer could make us buffer forever while waiting for a CRLF
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// A value of the Redis serialization protocol.
+/// Contains both the RESP2 types and the ones introduced in RESP3.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RespValue {
+    SimpleString(String),
+    Error(String),
+    Integer(i64),
+    /// None represents the RESP2 null bulk string
+    BulkString(Option<Vec<u8>>),
+    /// None represents the RESP2 null array
+    Array(Option<Vec<RespValue>>),
+    // RESP3 types
+    Null,
+    Boolean(bool),
+    Double(f64),
+    BigNumber(String),
+    BulkError(Vec<u8>),
+    VerbatimString(String, Vec<u8>),
+    Map(Vec<(RespValue, RespValue)>),
+    Set(Vec<RespValue>),
+    Attribute(Vec<(RespValue, RespValue)>),
+    Push(Vec<RespValue>),
+}
+
+/// The version of the protocol used when encoding values
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RespVersion {
+    /// RESP3 types are encoded as their closest RESP2 equivalent
+    Resp2,
+    Resp3,
+}
+
+/// A codec for the Redis serialization protocol.
+/// Decoding accepts both RESP2 and RESP3, while encoding uses the configured version
+/// (which should follow what was negotiated with HELLO)
+pub struct RespCodec {
+    version: RespVersion,
+    max_bulk_len: usize,
+}
+
+impl RespCodec {
+    pub fn new(version: RespVersion) -> Self {
+        Self::with_max_bulk_len(version, DEFAULT_MAX_BULK_LEN)
+    }
+
+    pub fn with_max_bulk_len(version: RespVersion, max_bulk_len: usize) -> Self {
+        RespCodec {
+            version,
+            max_bulk_len,
+        }
+    }
+
+    pub fn version(&self) -> RespVersion {
+        self.version
+    }
+
+    /// Change the version used to encode values, for example after a HELLO 3
+    pub fn set_version(&mut self, version: RespVersion) {
+        self.version = version;
+    }
+
+    /// Parse a value from the start of the buffer, returning it and the amount of bytes it used
+    fn parse(&self, buf: &[u8], depth: usize) -> io::Result<Option<(RespValue, usize)>> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        if depth > MAX_NESTING_DEPTH {
+            return Err(resp_error("Values nested too deeply"));
+        }
+
+        let line_end = match buf[1..].windows(2).position(|window| window == b"\r\n") {
+            Some(position) => position + 1,
+            None if buf.len() > MAX_LINE_LEN => return Err(resp_error("Line too long")),
+            None => return Ok(None)
+        };
+
+        let line = &buf[1..line_end];
+        let consumed = line_end + 2;
+
+        let value = match buf[0] {
+            b'+' => RespValue::SimpleString(parse_str(line)?.to_string()),
+            b'-' => RespValue::Error(parse_str(line)?.to_string()),
+            b':' => RespValue::Integer(parse_int(line)?),
+            b'_' => {
+                if !line.is_empty() {
+                    return Err(resp_error("Invalid null"));
+                }
+
+                RespValue::Null
+            }
+            b'#' => {
+                match line {
+                    b"t" => RespValue::Boolean(true),
+                    b"f" => RespValue::Boolean(false),
+                    _ => return Err(resp_error("Invalid boolean"))
+                }
+            }
+            b',' => {
+                let double = match parse_str(line)? {
+                    "inf" => f64::INFINITY,
+                    "-inf" => f64::NEG_INFINITY,
+                    "nan" => f64::NAN,
+                    other => other.parse().map_err(|_| resp_error("Invalid double"))?
+                };
+
+                RespValue::Double(double)
+            }
+            b'(' => RespValue::BigNumber(parse_str(line)?.to_string()),
+            b'$' | b'!' | b'=' => {
+                let len = parse_int(line)?;
+
+                if len == -1 && buf[0] == b'$' {
+                    return Ok(Some((RespValue::BulkString(None), consumed)));
+                }
+
+                if len < 0 || len as usize > self.max_bulk_len {
+                    return Err(resp_error("Invalid bulk length"));
+                }
+
+                let len = len as usize;
+
+                if buf.len() < consumed + len + 2 {
+                    return Ok(None);
+                }
+
+                if &buf[consumed + len..consumed + len + 2] != b"\r\n" {
+                    return Err(resp_error("Bulk data is not terminated by CRLF"));
+                }
+
+                let data = buf[consumed..consumed + len].to_vec();
+
+                let value = match buf[0] {
+                    b'$' => RespValue::BulkString(Some(data)),
+                    b'!' => RespValue::BulkError(data),
+                    _ => {
+                        //Verbatim strings are prefixed by their 3 character format and a colon
+                        if data.len() < 4 || data[3] != b':' {
+                            return Err(resp_error("Invalid verbatim string"));
+                        }
+
+                        let format = parse_str(&data[..3])?.to_string();
+
+                        RespValue::VerbatimString(format, data[4..].to_vec())
+                    }
+                };
+
+                return Ok(Some((value, consumed + len + 2)));
+            }
+            b'*' | b'~' | b'>' => {
+                let count = parse_int(line)?;
+
+                if count == -1 && buf[0] == b'*' {
+                    return Ok(Some((RespValue::Array(None), consumed)));
+                }
+
+                let (elements, consumed) = match self.parse_elements(buf, consumed, count, depth)? {
+                    Some(result) => result,
+                    None => return Ok(None)
+                };
+
+                let value = match buf[0] {
+                    b'*' => RespValue::Array(Some(elements)),
+                    b'~' => RespValue::Set(elements),
+                    _ => RespValue::Push(elements)
+                };
+
+                return Ok(Some((value, consumed)));
+            }
+            b'%' | b'|' => {
+                let count = parse_int(line)?;
+
+                if !(0..=i64::MAX / 2).contains(&count) {
+                    return Err(resp_error("Invalid map length"));
+                }
+
+                let (elements, consumed) = match self.parse_elements(buf, consumed, count * 2, depth)? {
+                    Some(result) => result,
+                    None => return Ok(None)
+                };
+
+                let mut pairs = Vec::with_capacity(elements.len() / 2);
+                let mut elements = elements.into_iter();
+
+                while let (Some(key), Some(value)) = (elements.next(), elements.next()) {
+                    pairs.push((key, value));
+                }
+
+                let value = match buf[0] {
+                    b'%' => RespValue::Map(pairs),
+                    _ => RespValue::Attribute(pairs)
+                };
+
+                return Ok(Some((value, consumed)));
+            }
+            _ => return Err(resp_error("Unknown type"))
+        };
+
+        Ok(Some((value, consumed)))
+    }
+
+    /// Parse count values, starting at the given offset of the buffer
+    fn parse_elements(&self, buf: &[u8], mut offset: usize, count: i64, depth: usize)
+                      -> io::Result<Option<(Vec<RespValue>, usize)>> {
+        if count < 0 {
+            return Err(resp_error("Invalid aggregate length"));
+        }
+
+        //Don't trust the announced length to pre allocate, as it could be huge
+        let mut elements = Vec::with_capacity((count as usize).min(1024));
+
+        for _ in 0..count {
+            match self.parse(&buf[offset..], depth + 1)? {
+                Some((value, consumed)) => {
+                    elements.push(value);
+                    offset += consumed;
+                }
+                None => return Ok(None)
+            }
+        }
+
+        Ok(Some((elements, offset)))
+    }
+
+    fn encode_value(&self, value: &RespValue, dst: &mut Vec<u8>) -> io::Result<()> {
+        let resp3 = self.version == RespVersion::Resp3;
+
+        match value {
+            RespValue::SimpleString(string) => write_text_line(dst, b'+', string.as_bytes())?,
+            RespValue::Error(error) => write_text_line(dst, b'-', error.as_bytes())?,
+            RespValue::Integer(integer) => write_line(dst, b':', integer.to_string().as_bytes()),
+            RespValue::BulkString(None) => write_line(dst, b'$', b"-1"),
+            RespValue::BulkString(Some(data)) => write_bulk(dst, b'$', data),
+            RespValue::Array(None) => write_line(dst, b'*', b"-1"),
+            RespValue::Array(Some(elements)) => self.encode_aggregate(dst, b'*', elements)?,
+            RespValue::Null if resp3 => write_line(dst, b'_', b""),
+            RespValue::Null => write_line(dst, b'$', b"-1"),
+            RespValue::Boolean(boolean) if resp3 => write_line(dst, b'#', if *boolean { b"t" } else { b"f" }),
+            RespValue::Boolean(boolean) => write_line(dst, b':', if *boolean { b"1" } else { b"0" }),
+            RespValue::Double(double) => {
+                //Rust formats these as inf, -inf and NaN, while RESP spells them inf, -inf and nan
+                let text = if double.is_nan() {
+                    "nan".to_string()
+                } else if double.is_infinite() {
+                    if double.is_sign_positive() { "inf".to_string() } else { "-inf".to_string() }
+                } else {
+                    double.to_string()
+                };
+
+                if resp3 {
+                    write_line(dst, b',', text.as_bytes())
+                } else {
+                    write_bulk(dst, b'$', text.as_bytes())
+                }
+            }
+            RespValue::BigNumber(number) if resp3 => write_text_line(dst, b'(', number.as_bytes())?,
+            RespValue::BigNumber(number) => write_bulk(dst, b'$', number.as_bytes()),
+            RespValue::BulkError(error) if resp3 => write_bulk(dst, b'!', error),
+            RespValue::BulkError(error) => write_text_line(dst, b'-', &error[..])?,
+            RespValue::VerbatimString(format, data) if resp3 => {
+                let mut verbatim = Vec::with_capacity(data.len() + 4);
+
+                verbatim.extend_from_slice(format.as_bytes());
+                verbatim.push(b':');
+                verbatim.extend_from_slice(data);
+
+                write_bulk(dst, b'=', &verbatim)
+            }
+            RespValue::VerbatimString(_, data) => write_bulk(dst, b'$', data),
+            RespValue::Map(pairs) | RespValue::Attribute(pairs) => {
+                let prefix = match (value, resp3) {
+                    (RespValue::Map(_), true) => b'%',
+                    (RespValue::Attribute(_), true) => b'|',
+                    //RESP2 represents maps as a flat array of keys and values
+                    _ => b'*'
+                };
+
+                let len = if resp3 { pairs.len() } else { pairs.len() * 2 };
+
+                write_line(dst, prefix, len.to_string().as_bytes());
+
+                for (key, value) in pairs {
+                    self.encode_value(key, dst)?;
+                    self.encode_value(value, dst)?;
+                }
+            }
+            RespValue::Set(elements) => self.encode_aggregate(dst, if resp3 { b'~' } else { b'*' }, elements)?,
+            RespValue::Push(elements) => self.encode_aggregate(dst, if resp3 { b'>' } else { b'*' }, elements)?,
+        }
+
+        Ok(())
+    }
+
+    fn encode_aggregate(&self, dst: &mut Vec<u8>, prefix: u8, elements: &[RespValue]) -> io::Result<()> {
+        write_line(dst, prefix, elements.len().to_string().as_bytes());
+
+        for element in elements {
+            self.encode_value(element, dst)?;
+        }
+
+        Ok(())
+    }
+
+    /// Encode the value, leaving the destination as it was if the value can't be encoded
+    fn encode_into(&self, value: &RespValue, dst: &mut Vec<u8>) -> io::Result<()> {
+        let len = dst.len();
+
+        let result = self.encode_value(value, dst);
+
+        if result.is_err() {
+            dst.truncate(len);
+        }
+
+        result
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = RespValue;
+
+    fn decode(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<RespValue>> {
+        //Since we do not keep partial parsing state, incomplete values are parsed again
+        //from the start when more bytes arrive.
+        match self.parse(buf, 0)? {
+            Some((value, consumed)) => {
+                buf.drain(..consumed);
+
+                Ok(Some(value))
+            }
+            None => Ok(None)
+        }
+    }
+}
+
+impl Encoder<RespValue> for RespCodec {
+    fn encode(&mut self, item: RespValue, dst: &mut Vec<u8>) -> io::Result<()> {
+        self.encode_into(&item, dst)
+    }
+}
+
+impl Encoder<&RespValue> for RespCodec {
+    fn encode(&mut self, item: &RespValue, dst: &mut Vec<u8>) -> io::Result<()> {
+        self.encode_into(item, dst)
+    }
+}
+
+fn write_line(dst: &mut Vec<u8>, prefix: u8, line: &[u8]) {
+    dst.push(prefix);
+    dst.extend_from_slice(line);
+    dst.extend_from_slice(b"\r\n");
+}
+
+/// Write a line whose content comes from the value being encoded.
+/// A line break in it would end the value early and have the rest parsed as other values
+fn write_text_line(dst: &mut Vec<u8>, prefix: u8, line: &[u8]) -> io::Result<()> {
+    if line.iter().any(|byte| *byte == b'\r' || *byte == b'\n') {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "RESP simple values can't contain CR or LF"));
+    }
+
+    write_line(dst, prefix, line);
+
+    Ok(())
+}
+
+fn write_bulk(dst: &mut Vec<u8>, prefix: u8, data: &[u8]) {
+    write_line(dst, prefix, data.len().to_string().as_bytes());
+    dst.extend_from_slice(data);
+    dst.extend_from_slice(b"\r\n");
+}
+
+fn parse_str(line: &[u8]) -> io::Result<&str> {
+    std::str::from_utf8(line).map_err(|_| resp_error("Invalid UTF-8"))
+}
+
+fn parse_int(line: &[u8]) -> io::Result<i64> {
+    parse_str(line)?.parse().map_err(|_| resp_error("Invalid integer"))
+}
+
+fn resp_error(reason: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("RESP protocol error: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(codec: &mut RespCodec, bytes: &[u8]) -> Vec<RespValue> {
+        let mut buf = bytes.to_vec();
+        let mut values = Vec::new();
+
+        while let Some(value) = codec.decode(&mut buf).unwrap() {
+            values.push(value);
+        }
+
+        assert!(buf.is_empty());
+
+        values
+    }
+
+    #[test]
+    fn decodes_resp2_command() {
+        let mut codec = RespCodec::new(RespVersion::Resp2);
+
+        let values = decode_all(&mut codec, b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n:-5\r\n$-1\r\n");
+
+        assert_eq!(values, vec![
+            RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"GET".to_vec())),
+                RespValue::BulkString(Some(b"key".to_vec())),
+            ])),
+            RespValue::Integer(-5),
+            RespValue::BulkString(None),
+        ]);
+    }
+
+    #[test]
+    fn waits_for_incomplete_values() {
+        let mut codec = RespCodec::new(RespVersion::Resp3);
+
+        let encoded = b"%1\r\n+key\r\n>2\r\n#t\r\n,1.5\r\n";
+
+        for split in 0..encoded.len() {
+            let mut buf = encoded[..split].to_vec();
+
+            assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        }
+
+        assert_eq!(decode_all(&mut codec, encoded), vec![RespValue::Map(vec![
+            (RespValue::SimpleString("key".to_string()),
+             RespValue::Push(vec![RespValue::Boolean(true), RespValue::Double(1.5)]))
+        ])]);
+    }
+
+    #[test]
+    fn encodes_resp3_values_as_resp2() {
+        let value = RespValue::Map(vec![(RespValue::SimpleString("a".to_string()), RespValue::Null)]);
+
+        let mut buf = Vec::new();
+
+        RespCodec::new(RespVersion::Resp2).encode(&value, &mut buf).unwrap();
+
+        assert_eq!(&buf[..], b"*2\r\n+a\r\n$-1\r\n");
+
+        buf.clear();
+
+        let mut codec = RespCodec::new(RespVersion::Resp3);
+
+        codec.encode(&value, &mut buf).unwrap();
+
+        assert_eq!(decode_all(&mut codec, &buf), vec![value]);
+    }
+
+    #[test]
+    fn encodes_non_finite_doubles() {
+        let mut codec = RespCodec::new(RespVersion::Resp3);
+
+        let mut buf = Vec::new();
+
+        for double in [f64::INFINITY, f64::NEG_INFINITY, f64::NAN] {
+            codec.encode(&RespValue::Double(double), &mut buf).unwrap();
+        }
+
+        assert_eq!(&buf[..], b",inf\r\n,-inf\r\n,nan\r\n");
+
+        let values = decode_all(&mut codec, &buf);
+
+        assert_eq!(values[..2], [RespValue::Double(f64::INFINITY), RespValue::Double(f64::NEG_INFINITY)]);
+        assert!(matches!(values[2], RespValue::Double(double) if double.is_nan()));
+
+        buf.clear();
+
+        RespCodec::new(RespVersion::Resp2).encode(&RespValue::Double(f64::NAN), &mut buf).unwrap();
+
+        assert_eq!(&buf[..], b"$3\r\nnan\r\n");
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let mut codec = RespCodec::new(RespVersion::Resp2);
+
+        assert!(codec.decode(&mut b"?what\r\n".to_vec()).is_err());
+        assert!(codec.decode(&mut b"$3\r\nabcd\r\n".to_vec()).is_err());
+    }
+
+    #[test]
+    fn rejects_line_breaks_in_simple_values() {
+        let injected = [
+            RespValue::SimpleString("OK\r\n+INJECTED".to_string()),
+            RespValue::Error("ERR\n".to_string()),
+            RespValue::Array(Some(vec![RespValue::Integer(1), RespValue::Error("ERR\r".to_string())])),
+        ];
+
+        for version in [RespVersion::Resp2, RespVersion::Resp3] {
+            let mut codec = RespCodec::new(version);
+
+            for value in &injected {
+                let mut buf = b"+previous\r\n".to_vec();
+
+                assert_eq!(codec.encode(value, &mut buf).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+                //Nothing is left behind from the values that were encoded before the failure
+                assert_eq!(&buf[..], b"+previous\r\n");
+            }
+        }
+
+        //RESP3 sends bulk errors with their length, RESP2 as a simple error
+        let bulk_error = RespValue::BulkError(b"ERR\r\nmultiple lines".to_vec());
+
+        assert!(RespCodec::new(RespVersion::Resp3).encode(&bulk_error, &mut Vec::new()).is_ok());
+        assert!(RespCodec::new(RespVersion::Resp2).encode(&bulk_error, &mut Vec::new()).is_err());
+    }
+}
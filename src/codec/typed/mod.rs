@@ -0,0 +1,156 @@
+use std::io;
+use std::io::ErrorKind;
+use std::marker::PhantomData;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::codec::{Decoder, Encoder};
+use crate::codec::lines::LinesCodec;
+use crate::codec::varint::VarintDelimitedCodec;
+
+/// A serialization format for serde types
+pub trait Format: Send {
+    /// Serialize the item, appending the result to dst
+    fn serialize<T>(&self, item: &T, dst: &mut Vec<u8>) -> io::Result<()> where T: Serialize;
+
+    fn deserialize<T>(&self, payload: &[u8]) -> io::Result<T> where T: DeserializeOwned;
+}
+
+/// The bincode serialization format
+pub struct Bincode;
+
+/// The JSON serialization format.
+/// Values are serialized in the compact form, so they never contain line breaks
+/// and can be used with [LinesCodec] for JSON lines
+pub struct Json;
+
+/// A codec that serializes and deserializes messages of type T with the given [Format],
+/// on top of a framing codec C that delimits the messages in the byte stream.
+pub struct SerdeCodec<T, F, C> where F: Format {
+    format: F,
+    framing: C,
+    _message: PhantomData<fn() -> T>,
+}
+
+impl<T, F, C> SerdeCodec<T, F, C> where F: Format {
+    pub fn new(format: F, framing: C) -> Self {
+        SerdeCodec {
+            format,
+            framing,
+            _message: PhantomData,
+        }
+    }
+
+    pub fn format(&self) -> &F {
+        &self.format
+    }
+
+    pub fn framing(&self) -> &C {
+        &self.framing
+    }
+}
+
+impl<T> SerdeCodec<T, Bincode, VarintDelimitedCodec> {
+    /// Bincode messages, delimited by their varint encoded length
+    pub fn bincode(max_message_size: usize) -> Self {
+        Self::new(Bincode, VarintDelimitedCodec::new(max_message_size))
+    }
+}
+
+impl<T> SerdeCodec<T, Json, LinesCodec> {
+    /// JSON lines, one message per line
+    pub fn json_lines(max_message_size: usize) -> Self {
+        Self::new(Json, LinesCodec::new(max_message_size))
+    }
+}
+
+impl<T, F, C> Decoder for SerdeCodec<T, F, C>
+    where T: DeserializeOwned,
+          F: Format,
+          C: Decoder<Item=Vec<u8>> {
+    type Item = T;
+
+    fn decode(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<T>> {
+        match self.framing.decode(buf)? {
+            Some(payload) => self.format.deserialize(&payload).map(Some),
+            None => Ok(None)
+        }
+    }
+}
+
+impl<T, F, C> Encoder<T> for SerdeCodec<T, F, C>
+    where T: Serialize,
+          F: Format,
+          C: Encoder<Vec<u8>> {
+    fn encode(&mut self, item: T, dst: &mut Vec<u8>) -> io::Result<()> {
+        self.encode(&item, dst)
+    }
+}
+
+impl<T, F, C> Encoder<&T> for SerdeCodec<T, F, C>
+    where T: Serialize,
+          F: Format,
+          C: Encoder<Vec<u8>> {
+    fn encode(&mut self, item: &T, dst: &mut Vec<u8>) -> io::Result<()> {
+        let mut payload = Vec::new();
+
+        self.format.serialize(item, &mut payload)?;
+
+        self.framing.encode(payload, dst)
+    }
+}
+
+impl Format for Bincode {
+    fn serialize<T>(&self, item: &T, dst: &mut Vec<u8>) -> io::Result<()> where T: Serialize {
+        bincode::serialize_into(dst, item)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))
+    }
+
+    fn deserialize<T>(&self, payload: &[u8]) -> io::Result<T> where T: DeserializeOwned {
+        bincode::deserialize(payload)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+    }
+}
+
+impl Format for Json {
+    fn serialize<T>(&self, item: &T, dst: &mut Vec<u8>) -> io::Result<()> where T: Serialize {
+        serde_json::to_writer(dst, item)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))
+    }
+
+    fn deserialize<T>(&self, payload: &[u8]) -> io::Result<T> where T: DeserializeOwned {
+        serde_json::from_slice(payload)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Message = (u32, String, Vec<i64>);
+
+    fn round_trip<F, C>(mut codec: SerdeCodec<Message, F, C>)
+        where F: Format, C: Decoder<Item=Vec<u8>> + Encoder<Vec<u8>> {
+        let first: Message = (1, "first".to_string(), vec![-1, 2]);
+        let second: Message = (2, "second\nline".to_string(), vec![]);
+
+        let mut buf = Vec::new();
+
+        codec.encode(&first, &mut buf).unwrap();
+        codec.encode(second.clone(), &mut buf).unwrap();
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(first));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(second));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn bincode_round_trip() {
+        round_trip(SerdeCodec::bincode(1024));
+    }
+
+    #[test]
+    fn json_lines_round_trip() {
+        round_trip(SerdeCodec::json_lines(1024));
+    }
+}
@@ -0,0 +1,183 @@
+use std::io;
+use std::io::ErrorKind;
+use std::marker::PhantomData;
+use crate::codec::{Decoder, Encoder};
+
+/// A u64 encoded in LEB128 takes at most 10 bytes
+const MAX_VARINT_LEN: usize = 10;
+
+/// A message that can be sent through a [VarintDelimitedCodec].
+/// Implement this to plug in the deserialization of your own message types
+/// (for example, protobuf messages)
+pub trait DelimitedMessage: Sized + Send {
+    /// Deserialize the message from its payload (without the length prefix)
+    fn decode_message(payload: Vec<u8>) -> io::Result<Self>;
+
+    /// Serialize the message, appending its payload (without the length prefix) to dst
+    fn encode_message(&self, dst: &mut Vec<u8>) -> io::Result<()>;
+}
+
+/// A codec for messages prefixed by their length, encoded as an unsigned LEB128 varint
+/// (the format used by protobuf's delimited streams).
+/// By default it produces the raw payload of each message.
+pub struct VarintDelimitedCodec<T = Vec<u8>> where T: DelimitedMessage {
+    max_message_size: usize,
+    _message: PhantomData<fn() -> T>,
+}
+
+impl<T> VarintDelimitedCodec<T> where T: DelimitedMessage {
+    /// Create a new codec. Messages larger than max_message_size are refused,
+    /// both when decoding and encoding
+    pub fn new(max_message_size: usize) -> Self {
+        VarintDelimitedCodec {
+            max_message_size,
+            _message: PhantomData,
+        }
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+}
+
+impl<T> Decoder for VarintDelimitedCodec<T> where T: DelimitedMessage {
+    type Item = T;
+
+    fn decode(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<T>> {
+        let (len, prefix_len) = match decode_varint(buf)? {
+            Some(result) => result,
+            None => return Ok(None)
+        };
+
+        if len > self.max_message_size as u64 {
+            return Err(io::Error::new(ErrorKind::InvalidData,
+                                      format!("Message of {} bytes exceeds the maximum size of {}", len, self.max_message_size)));
+        }
+
+        let len = len as usize;
+
+        if buf.len() < prefix_len + len {
+            buf.reserve(prefix_len + len - buf.len());
+
+            return Ok(None);
+        }
+
+        let payload = buf.drain(..prefix_len + len).skip(prefix_len).collect();
+
+        T::decode_message(payload).map(Some)
+    }
+}
+
+impl<T> Encoder<T> for VarintDelimitedCodec<T> where T: DelimitedMessage {
+    fn encode(&mut self, item: T, dst: &mut Vec<u8>) -> io::Result<()> {
+        let mut payload = Vec::new();
+
+        item.encode_message(&mut payload)?;
+
+        if payload.len() > self.max_message_size {
+            return Err(io::Error::new(ErrorKind::InvalidInput,
+                                      format!("Message of {} bytes exceeds the maximum size of {}", payload.len(), self.max_message_size)));
+        }
+
+        encode_varint(payload.len() as u64, dst);
+
+        dst.extend_from_slice(&payload);
+
+        Ok(())
+    }
+}
+
+impl DelimitedMessage for Vec<u8> {
+    fn decode_message(payload: Vec<u8>) -> io::Result<Self> {
+        Ok(payload)
+    }
+
+    fn encode_message(&self, dst: &mut Vec<u8>) -> io::Result<()> {
+        dst.extend_from_slice(self);
+
+        Ok(())
+    }
+}
+
+/// Append the LEB128 encoding of the value to dst
+pub fn encode_varint(mut value: u64, dst: &mut Vec<u8>) {
+    while value >= 0x80 {
+        dst.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+
+    dst.push(value as u8);
+}
+
+/// Decode a LEB128 varint from the start of the buffer.
+/// Returns the value and how many bytes it took up, or None if the buffer ends before the varint does.
+pub fn decode_varint(buf: &[u8]) -> io::Result<Option<(u64, usize)>> {
+    let mut value: u64 = 0;
+
+    for (i, byte) in buf.iter().take(MAX_VARINT_LEN).enumerate() {
+        let bits = (byte & 0x7F) as u64;
+
+        //The 10th byte can only contribute the most significant bit
+        if i == MAX_VARINT_LEN - 1 && bits > 1 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Varint overflows a u64"));
+        }
+
+        value |= bits << (7 * i);
+
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, i + 1)));
+        }
+    }
+
+    if buf.len() >= MAX_VARINT_LEN {
+        Err(io::Error::new(ErrorKind::InvalidData, "Varint is longer than 10 bytes"))
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_round_trip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+
+            encode_varint(value, &mut buf);
+
+            assert_eq!(decode_varint(&buf).unwrap(), Some((value, buf.len())));
+            assert_eq!(decode_varint(&buf[..buf.len() - 1]).unwrap(), None);
+        }
+
+        assert!(decode_varint(&[0xFF; 11]).is_err());
+    }
+
+    #[test]
+    fn decodes_split_messages_and_enforces_max_size() {
+        let mut codec: VarintDelimitedCodec = VarintDelimitedCodec::new(300);
+
+        let mut encoded = Vec::new();
+
+        codec.encode(vec![1; 200], &mut encoded).unwrap();
+        codec.encode(vec![2; 3], &mut encoded).unwrap();
+
+        let mut buf = encoded[..150].to_vec();
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(&encoded[150..]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(vec![1; 200]));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(vec![2; 3]));
+
+        assert!(codec.encode(vec![0; 301], &mut Vec::new()).is_err());
+
+        let mut too_big = Vec::new();
+
+        encode_varint(301, &mut too_big);
+
+        assert!(codec.decode(&mut too_big).is_err());
+    }
+}
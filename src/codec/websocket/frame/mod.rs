@@ -0,0 +1,341 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use crate::codec::{Decoder, Encoder};
+use crate::codec::websocket::Role;
+use crate::util::random_u64;
+
+/// The largest payload a control frame (close, ping, pong) may carry, as per RFC 6455
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpCode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+/// A single websocket frame.
+/// Messages may be split across various frames (fragmentation), in which case
+/// only the first frame carries the message's op code and the following ones
+/// are marked as [OpCode::Continuation]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    fin: bool,
+    op_code: OpCode,
+    payload: Vec<u8>,
+}
+
+/// Errors that can occur when decoding websocket frames.
+/// These are reported wrapped in an [io::Error] of kind [ErrorKind::InvalidData]
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The peer violated the websocket protocol
+    Protocol(&'static str),
+    /// The frame (or message) exceeds the configured maximum size
+    TooBig(usize),
+}
+
+/// The codec for websocket frames.
+/// Frames sent by the client must be masked, while frames sent by the server must not be,
+/// so the codec needs to know which side of the connection it's on.
+pub struct FrameCodec {
+    role: Role,
+    max_frame_size: usize,
+}
+
+impl OpCode {
+    fn from_u8(op_code: u8) -> Option<Self> {
+        match op_code {
+            0x0 => Some(OpCode::Continuation),
+            0x1 => Some(OpCode::Text),
+            0x2 => Some(OpCode::Binary),
+            0x8 => Some(OpCode::Close),
+            0x9 => Some(OpCode::Ping),
+            0xA => Some(OpCode::Pong),
+            _ => None
+        }
+    }
+
+    fn as_u8(&self) -> u8 {
+        match self {
+            OpCode::Continuation => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xA,
+        }
+    }
+
+    /// Control frames are the ones that are not part of a message's data
+    pub fn is_control(&self) -> bool {
+        matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
+    }
+}
+
+impl Frame {
+    pub fn new(fin: bool, op_code: OpCode, payload: Vec<u8>) -> Self {
+        Frame {
+            fin,
+            op_code,
+            payload,
+        }
+    }
+
+    pub fn fin(&self) -> bool {
+        self.fin
+    }
+
+    pub fn op_code(&self) -> OpCode {
+        self.op_code
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn into_payload(self) -> Vec<u8> {
+        self.payload
+    }
+}
+
+impl FrameCodec {
+    pub fn new(role: Role, max_frame_size: usize) -> Self {
+        FrameCodec {
+            role,
+            max_frame_size,
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+
+    fn decode(&mut self, buf: &mut Vec<u8>) -> io::Result<Option<Frame>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+
+        let fin = buf[0] & 0x80 != 0;
+
+        if buf[0] & 0x70 != 0 {
+            //We do not negotiate any extensions, so the reserved bits must not be set
+            return Err(FrameError::Protocol("Reserved bits set").into());
+        }
+
+        let op_code = OpCode::from_u8(buf[0] & 0x0F)
+            .ok_or(FrameError::Protocol("Unknown op code"))?;
+
+        let masked = buf[1] & 0x80 != 0;
+
+        match self.role {
+            Role::Server if !masked => {
+                return Err(FrameError::Protocol("Client frames must be masked").into());
+            }
+            Role::Client if masked => {
+                return Err(FrameError::Protocol("Server frames must not be masked").into());
+            }
+            _ => {}
+        }
+
+        let (payload_len, mut header_len) = match buf[1] & 0x7F {
+            126 => {
+                if buf.len() < 4 {
+                    return Ok(None);
+                }
+
+                (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4)
+            }
+            127 => {
+                if buf.len() < 10 {
+                    return Ok(None);
+                }
+
+                let mut len_bytes = [0; 8];
+
+                len_bytes.copy_from_slice(&buf[2..10]);
+
+                let len = u64::from_be_bytes(len_bytes);
+
+                if len & (1 << 63) != 0 {
+                    return Err(FrameError::Protocol("Most significant bit of the length set").into());
+                }
+
+                (len, 10)
+            }
+            len => (len as u64, 2)
+        };
+
+        if op_code.is_control() && (!fin || payload_len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(FrameError::Protocol("Control frames must not be fragmented or larger than 125 bytes").into());
+        }
+
+        if payload_len > self.max_frame_size as u64 {
+            return Err(FrameError::TooBig(payload_len as usize).into());
+        }
+
+        let payload_len = payload_len as usize;
+
+        let mask = if masked {
+            if buf.len() < header_len + 4 {
+                return Ok(None);
+            }
+
+            let mut mask = [0; 4];
+
+            mask.copy_from_slice(&buf[header_len..header_len + 4]);
+
+            header_len += 4;
+
+            Some(mask)
+        } else {
+            None
+        };
+
+        if buf.len() < header_len + payload_len {
+            //The frame is not complete yet
+            return Ok(None);
+        }
+
+        let mut payload: Vec<u8> = buf.drain(..header_len + payload_len)
+            .skip(header_len)
+            .collect();
+
+        if let Some(mask) = mask {
+            apply_mask(&mut payload, mask);
+        }
+
+        Ok(Some(Frame::new(fin, op_code, payload)))
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    fn encode(&mut self, item: Frame, dst: &mut Vec<u8>) -> io::Result<()> {
+        let Frame { fin, op_code, mut payload } = item;
+
+        let fin_bit = if fin { 0x80 } else { 0x00 };
+
+        dst.push(fin_bit | op_code.as_u8());
+
+        //Clients must mask every frame they send
+        let mask_bit = match self.role {
+            Role::Client => 0x80,
+            Role::Server => 0x00
+        };
+
+        let len = payload.len();
+
+        if len < 126 {
+            dst.push(mask_bit | len as u8);
+        } else if len <= u16::MAX as usize {
+            dst.push(mask_bit | 126);
+            dst.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            dst.push(mask_bit | 127);
+            dst.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+
+        if let Role::Client = self.role {
+            let mask = (random_u64() as u32).to_be_bytes();
+
+            dst.extend_from_slice(&mask);
+
+            apply_mask(&mut payload, mask);
+        }
+
+        dst.extend_from_slice(&payload);
+
+        Ok(())
+    }
+}
+
+/// Masking and unmasking are the same operation
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::Protocol(reason) => write!(f, "Websocket protocol error: {}", reason),
+            FrameError::TooBig(size) => write!(f, "Websocket message of {} bytes exceeds the maximum size", size),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for io::Error {
+    fn from(err: FrameError) -> Self {
+        io::Error::new(ErrorKind::InvalidData, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_frames_are_masked_and_decoded_by_server() {
+        let mut client = FrameCodec::new(Role::Client, 1024);
+        let mut server = FrameCodec::new(Role::Server, 1024);
+
+        let mut buf = Vec::new();
+
+        client.encode(Frame::new(true, OpCode::Text, b"Hello".to_vec()), &mut buf).unwrap();
+
+        assert_eq!(buf[1] & 0x80, 0x80);
+
+        let frame = server.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(frame, Frame::new(true, OpCode::Text, b"Hello".to_vec()));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn partial_frames_wait_for_more_bytes() {
+        let mut server = FrameCodec::new(Role::Server, 1 << 20);
+        let mut client = FrameCodec::new(Role::Client, 1 << 20);
+
+        let mut encoded = Vec::new();
+
+        client.encode(Frame::new(true, OpCode::Binary, vec![7; 70_000]), &mut encoded).unwrap();
+
+        let mut buf = encoded[..100].to_vec();
+
+        assert_eq!(server.decode(&mut buf).unwrap(), None);
+        assert_eq!(buf.len(), 100);
+
+        buf.extend_from_slice(&encoded[100..]);
+
+        let frame = server.decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(frame.payload(), &vec![7; 70_000][..]);
+    }
+
+    #[test]
+    fn rejects_unmasked_client_frames_and_oversized_frames() {
+        let mut server = FrameCodec::new(Role::Server, 4);
+
+        let mut unmasked = vec![0x81, 0x01, b'a'];
+
+        assert_eq!(server.decode(&mut unmasked).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let mut client = FrameCodec::new(Role::Client, 4);
+        let mut too_big = Vec::new();
+
+        client.encode(Frame::new(true, OpCode::Binary, vec![0; 5]), &mut too_big).unwrap();
+
+        assert!(server.decode(&mut too_big).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use std::io;
+use std::io::ErrorKind;
+use sha1::{Digest, Sha1};
+use crate::util::random_u64;
+
+/// The GUID that is appended to the client's key to compute the accept key (RFC 6455)
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The maximum size of the upgrade request/response head we are willing to buffer
+pub(super) const MAX_HANDSHAKE_SIZE: usize = 8192;
+
+/// The result of attempting to parse a handshake from the received bytes
+pub(super) enum HandshakeResult<T> {
+    /// We do not have the full HTTP head yet
+    Incomplete,
+    /// The handshake was parsed. Also contains the amount of bytes it took up
+    Complete(T, usize),
+}
+
+/// Compute the value of the Sec-WebSocket-Accept header for a given Sec-WebSocket-Key
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+
+    hasher.update(key.trim().as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+
+    base64::encode(hasher.finalize())
+}
+
+/// Generate a new random Sec-WebSocket-Key for a client handshake
+pub(super) fn generate_key() -> String {
+    let mut key = [0; 16];
+
+    key[..8].copy_from_slice(&random_u64().to_ne_bytes());
+    key[8..].copy_from_slice(&random_u64().to_ne_bytes());
+
+    base64::encode(key)
+}
+
+/// Parse the upgrade request sent by a client.
+/// Returns the response that should be sent to accept the upgrade.
+pub(super) fn parse_upgrade_request(buf: &[u8]) -> io::Result<HandshakeResult<Vec<u8>>> {
+    let (head, head_len) = match http_head(buf)? {
+        Some(head) => head,
+        None => return Ok(HandshakeResult::Incomplete)
+    };
+
+    let mut lines = head.split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+
+    if !request_line.starts_with("GET ") {
+        return Err(handshake_error("Upgrade request must be a GET"));
+    }
+
+    let headers: Vec<(&str, &str)> = lines.filter_map(parse_header).collect();
+
+    if !header_contains(&headers, "upgrade", "websocket") ||
+        !header_contains(&headers, "connection", "upgrade") {
+        return Err(handshake_error("Missing upgrade headers"));
+    }
+
+    if header(&headers, "sec-websocket-version") != Some("13") {
+        return Err(handshake_error("Unsupported websocket version"));
+    }
+
+    let key = header(&headers, "sec-websocket-key")
+        .ok_or_else(|| handshake_error("Missing Sec-WebSocket-Key"))?;
+
+    let response = format!("HTTP/1.1 101 Switching Protocols\r\n\
+                            Upgrade: websocket\r\n\
+                            Connection: Upgrade\r\n\
+                            Sec-WebSocket-Accept: {}\r\n\r\n", accept_key(key));
+
+    Ok(HandshakeResult::Complete(response.into_bytes(), head_len))
+}
+
+/// The response sent to clients whose upgrade request we could not accept
+pub(super) fn bad_request_response() -> &'static [u8] {
+    b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+}
+
+/// Build the upgrade request a client sends to the server
+pub(super) fn upgrade_request(host: &str, path: &str, key: &str) -> Vec<u8> {
+    format!("GET {} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\n\
+             Sec-WebSocket-Version: 13\r\n\r\n", path, host, key).into_bytes()
+}
+
+/// Parse the server's response to our upgrade request, validating the accept key
+pub(super) fn parse_upgrade_response(buf: &[u8], key: &str) -> io::Result<HandshakeResult<()>> {
+    let (head, head_len) = match http_head(buf)? {
+        Some(head) => head,
+        None => return Ok(HandshakeResult::Incomplete)
+    };
+
+    let mut lines = head.split("\r\n");
+
+    let status_line = lines.next().unwrap_or_default();
+
+    if status_line.split_whitespace().nth(1) != Some("101") {
+        return Err(handshake_error("Server refused the upgrade"));
+    }
+
+    let headers: Vec<(&str, &str)> = lines.filter_map(parse_header).collect();
+
+    if header(&headers, "sec-websocket-accept") != Some(accept_key(key).as_str()) {
+        return Err(handshake_error("Invalid Sec-WebSocket-Accept"));
+    }
+
+    Ok(HandshakeResult::Complete((), head_len))
+}
+
+/// Get the HTTP head (request/status line and headers) if we have received all of it
+fn http_head(buf: &[u8]) -> io::Result<Option<(&str, usize)>> {
+    let end = buf.windows(4).position(|window| window == b"\r\n\r\n");
+
+    match end {
+        Some(end) => {
+            let head = std::str::from_utf8(&buf[..end])
+                .map_err(|_| handshake_error("Handshake is not valid UTF-8"))?;
+
+            Ok(Some((head, end + 4)))
+        }
+        None if buf.len() > MAX_HANDSHAKE_SIZE => {
+            Err(handshake_error("Handshake too large"))
+        }
+        None => Ok(None)
+    }
+}
+
+fn parse_header(line: &str) -> Option<(&str, &str)> {
+    let (name, value) = line.split_once(':')?;
+
+    Some((name.trim(), value.trim()))
+}
+
+fn header<'a>(headers: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+        .map(|(_, value)| *value)
+}
+
+/// Check if a comma separated header contains the given token (case insensitive)
+fn header_contains(headers: &[(&str, &str)], name: &str, token: &str) -> bool {
+    header(headers, name)
+        .map(|value| value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token)))
+        .unwrap_or(false)
+}
+
+fn handshake_error(reason: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("Websocket handshake failed: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn client_and_server_handshakes_agree() {
+        let key = generate_key();
+
+        let request = upgrade_request("localhost", "/live", &key);
+
+        let response = match parse_upgrade_request(&request).unwrap() {
+            HandshakeResult::Complete(response, len) => {
+                assert_eq!(len, request.len());
+                response
+            }
+            HandshakeResult::Incomplete => panic!("Request should be complete")
+        };
+
+        assert!(matches!(parse_upgrade_response(&response, &key).unwrap(), HandshakeResult::Complete((), _)));
+        assert!(matches!(parse_upgrade_request(&request[..10]).unwrap(), HandshakeResult::Incomplete));
+    }
+}
@@ -0,0 +1,636 @@
+mod frame;
+mod handshake;
+
+use std::io;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use log::{debug, error};
+use crate::channel::Channel;
+use crate::channel::attributes::AttributeKey;
+use crate::error::RusttyError;
+use crate::codec::{Decoder, Encoder};
+use crate::future::{ready, WriteFuture};
+use crate::util::{ChannelHandler, LockExt};
+
+pub use frame::{Frame, FrameCodec, FrameError, OpCode};
+pub use handshake::accept_key;
+use handshake::HandshakeResult;
+
+/// Close status codes, as defined in RFC 6455
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_PAYLOAD: u16 = 1007;
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Which side of the websocket connection we are on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+/// A complete (reassembled) websocket message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// Configuration for websocket connections
+pub struct WebSocketConfig {
+    /// The maximum size of a message, after reassembling all of its fragments.
+    /// Peers that exceed it are disconnected with [CLOSE_MESSAGE_TOO_BIG]
+    max_message_size: usize,
+}
+
+/// A handle to an open websocket connection.
+/// Can be cloned and kept around to send messages outside of the handler callbacks
+#[derive(Clone)]
+pub struct WebSocket {
+    channel: Arc<Channel>,
+    role: Role,
+    close_sent: Arc<AtomicBool>,
+}
+
+/// A handler for the messages received through websocket connections
+pub trait WebSocketMessageHandler: Sync + Send {
+    /// Handle the upgrade handshake having been completed
+    fn handle_open(&self, _socket: &WebSocket) {}
+
+    /// Handle a new message being received
+    fn handle_message(&self, socket: &WebSocket, message: Message);
+
+    /// Handle the websocket being closed.
+    /// The code is the status code sent by the peer in its close frame, if any
+    fn handle_close(&self, _socket: &WebSocket, _code: Option<u16>) {}
+}
+
+/// A [ChannelHandler] that speaks the websocket protocol on top of the channel,
+/// performing the upgrade handshake, the frame decoding and fragment reassembly and
+/// answering pings and close requests, delivering only complete messages to the
+/// [WebSocketMessageHandler]
+pub struct WebSocketHandler<H> where H: WebSocketMessageHandler {
+    handler: H,
+    role: Role,
+    config: WebSocketConfig,
+    /// The host and path to request the upgrade for, when we are the client
+    client_target: Option<(String, String)>,
+}
+
+enum SessionState {
+    /// Waiting for the upgrade handshake. Clients keep the key they sent
+    Handshake(Option<String>),
+    Open(WebSocket),
+    /// The socket is only present if the handshake had been completed
+    Closed(Option<WebSocket>),
+}
+
+/// The attribute the websocket state of each channel is kept in
+const SESSION: AttributeKey<Mutex<Session>> = AttributeKey::new("rustty.websocket.session");
+
+/// The per channel websocket state
+struct Session {
+    state: SessionState,
+    buffer: Vec<u8>,
+    codec: FrameCodec,
+    /// The op code and payload of a message that is being received in fragments
+    fragmented: Option<(OpCode, Vec<u8>)>,
+    /// The code received in the peer's close frame
+    close_code: Option<u16>,
+}
+
+impl WebSocketConfig {
+    pub fn new(max_message_size: usize) -> Self {
+        WebSocketConfig {
+            max_message_size,
+        }
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig::new(16 * 1024 * 1024)
+    }
+}
+
+impl WebSocket {
+    fn new(channel: Arc<Channel>, role: Role) -> Self {
+        WebSocket {
+            channel,
+            role,
+            close_sent: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn channel(&self) -> &Arc<Channel> {
+        &self.channel
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Send a message to the peer, in a single frame
+    pub fn send(&self, message: Message) -> io::Result<()> {
+        let frame = match message {
+            Message::Text(text) => Frame::new(true, OpCode::Text, text.into_bytes()),
+            Message::Binary(data) => Frame::new(true, OpCode::Binary, data),
+        };
+
+        self.send_frame(frame)
+    }
+
+    pub fn ping(&self, payload: Vec<u8>) -> io::Result<()> {
+        self.send_frame(Frame::new(true, OpCode::Ping, payload))
+    }
+
+    /// Start the close handshake.
+    /// The channel is closed once the peer answers with its own close frame
+    pub fn close(&self, code: u16, reason: &str) -> io::Result<()> {
+        match self.close_frame(code, reason) {
+            Some(frame) => self.send_frame(frame),
+            None => Ok(())
+        }
+    }
+
+    /// Send a raw frame to the peer, masking it if we are the client
+    pub fn send_frame(&self, frame: Frame) -> io::Result<()> {
+        (&*self.channel).write_all(&self.encode(frame)?)
+    }
+
+    /// Send a raw frame to the peer, like [WebSocket::send_frame].
+    /// The returned future completes once the frame has actually been written to the socket
+    pub fn send_frame_async(&self, frame: Frame) -> WriteFuture {
+        match self.encode(frame) {
+            Ok(buf) => self.channel.send_async(buf),
+            Err(err) => ready(Err(err))
+        }
+    }
+
+    fn encode(&self, frame: Frame) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(frame.payload().len() + 14);
+
+        FrameCodec::new(self.role, usize::MAX).encode(frame, &mut buf)?;
+
+        Ok(buf)
+    }
+
+    /// The close frame to send, unless we have already sent one
+    fn close_frame(&self, code: u16, reason: &str) -> Option<Frame> {
+        if self.close_sent.swap(true, Ordering::SeqCst) {
+            //We have already sent a close frame, we can't send anything else
+            return None;
+        }
+
+        let mut payload = Vec::with_capacity(2 + reason.len());
+
+        payload.extend_from_slice(&code.to_be_bytes());
+        payload.extend_from_slice(reason.as_bytes());
+
+        Some(Frame::new(true, OpCode::Close, payload))
+    }
+
+    /// Send our close frame (if we haven't yet) and close the channel once it has been written,
+    /// as closing it right away would discard the frame if it's still waiting to be written
+    fn disconnect(&self, code: u16) {
+        let flushed = match self.close_frame(code, "") {
+            Some(frame) => self.send_frame_async(frame),
+            None => ready(Ok(()))
+        };
+
+        let channel = self.channel.clone();
+
+        flushed.on_complete(move |_| channel.close());
+    }
+
+    fn close_sent(&self) -> bool {
+        self.close_sent.load(Ordering::SeqCst)
+    }
+}
+
+impl<H> WebSocketHandler<H> where H: WebSocketMessageHandler {
+    /// Accept websocket upgrades from the clients that connect to us
+    pub fn server(handler: H, config: WebSocketConfig) -> Self {
+        Self::new(handler, Role::Server, config, None)
+    }
+
+    /// Request a websocket upgrade for the given host and path as soon as the connection
+    /// is established
+    pub fn client(handler: H, host: String, path: String, config: WebSocketConfig) -> Self {
+        Self::new(handler, Role::Client, config, Some((host, path)))
+    }
+
+    fn new(handler: H, role: Role, config: WebSocketConfig, client_target: Option<(String, String)>) -> Self {
+        WebSocketHandler {
+            handler,
+            role,
+            config,
+            client_target,
+        }
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    fn session(&self, channel: &Channel) -> Arc<Mutex<Session>> {
+        channel.attributes().get_or_insert_with(&SESSION, || Mutex::new(Session::new(self.role, None, self.config.max_message_size)))
+    }
+
+    /// Process the bytes that are buffered in the session
+    fn process(&self, channel: &Arc<Channel>, session: &mut Session) -> io::Result<()> {
+        if let SessionState::Handshake(client_key) = &session.state {
+            let handshake_len = match client_key {
+                None => {
+                    match handshake::parse_upgrade_request(&session.buffer) {
+                        Ok(HandshakeResult::Complete(response, len)) => {
+                            (&**channel).write_all(&response)?;
+
+                            len
+                        }
+                        Ok(HandshakeResult::Incomplete) => return Ok(()),
+                        Err(err) => {
+                            //We don't care if this fails, since we are closing the connection anyway
+                            let _ = (&**channel).write_all(handshake::bad_request_response());
+
+                            return Err(err);
+                        }
+                    }
+                }
+                Some(key) => {
+                    match handshake::parse_upgrade_response(&session.buffer, key)? {
+                        HandshakeResult::Complete((), len) => len,
+                        HandshakeResult::Incomplete => return Ok(()),
+                    }
+                }
+            };
+
+            session.buffer.drain(..handshake_len);
+
+            let socket = WebSocket::new(channel.clone(), self.role);
+
+            self.handler.handle_open(&socket);
+
+            session.state = SessionState::Open(socket);
+        }
+
+        while let SessionState::Open(socket) = &session.state {
+            let frame = match session.codec.decode(&mut session.buffer) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(err) => {
+                    let code = match err.get_ref().and_then(|inner| inner.downcast_ref::<FrameError>()) {
+                        Some(FrameError::TooBig(_)) => CLOSE_MESSAGE_TOO_BIG,
+                        _ => CLOSE_PROTOCOL_ERROR
+                    };
+
+                    debug!("Closing websocket connection {} because {:?}", channel.id(), err);
+
+                    socket.disconnect(code);
+
+                    session.mark_closed();
+
+                    return Ok(());
+                }
+            };
+
+            let socket = socket.clone();
+
+            if let Err(code) = self.handle_frame(&socket, session, frame) {
+                socket.disconnect(code);
+
+                session.mark_closed();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle a single frame, returning the close code to disconnect the peer with
+    /// if it violated the protocol
+    fn handle_frame(&self, socket: &WebSocket, session: &mut Session, frame: Frame) -> Result<(), u16> {
+        match frame.op_code() {
+            OpCode::Ping => {
+                if !socket.close_sent() {
+                    socket.send_frame(Frame::new(true, OpCode::Pong, frame.into_payload()))
+                        .map_err(|_| CLOSE_NORMAL)?;
+                }
+            }
+            OpCode::Pong => {}
+            OpCode::Close => {
+                let payload = frame.payload();
+
+                let code = if payload.len() >= 2 {
+                    Some(u16::from_be_bytes([payload[0], payload[1]]))
+                } else {
+                    None
+                };
+
+                session.close_code = code;
+
+                //Echo the close frame if we did not start the close handshake.
+                //If we did, the peer answering means it has already received ours
+                socket.disconnect(code.unwrap_or(CLOSE_NORMAL));
+
+                session.mark_closed();
+            }
+            OpCode::Text | OpCode::Binary => {
+                if session.fragmented.is_some() {
+                    //A new message can't begin while another one is still being received
+                    return Err(CLOSE_PROTOCOL_ERROR);
+                }
+
+                if frame.fin() {
+                    self.deliver(socket, frame.op_code(), frame.into_payload())?;
+                } else {
+                    session.fragmented = Some((frame.op_code(), frame.into_payload()));
+                }
+            }
+            OpCode::Continuation => {
+                let (op_code, mut payload) = session.fragmented.take()
+                    .ok_or(CLOSE_PROTOCOL_ERROR)?;
+
+                if payload.len() + frame.payload().len() > self.config.max_message_size {
+                    return Err(CLOSE_MESSAGE_TOO_BIG);
+                }
+
+                payload.extend_from_slice(frame.payload());
+
+                if frame.fin() {
+                    self.deliver(socket, op_code, payload)?;
+                } else {
+                    session.fragmented = Some((op_code, payload));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn deliver(&self, socket: &WebSocket, op_code: OpCode, payload: Vec<u8>) -> Result<(), u16> {
+        let message = match op_code {
+            OpCode::Text => {
+                Message::Text(String::from_utf8(payload).map_err(|_| CLOSE_INVALID_PAYLOAD)?)
+            }
+            _ => Message::Binary(payload)
+        };
+
+        self.handler.handle_message(socket, message);
+
+        Ok(())
+    }
+}
+
+impl<H> ChannelHandler for WebSocketHandler<H> where H: WebSocketMessageHandler {
+    fn handle_connection_established(&self, channel: Channel) -> Channel {
+        let client_key = if let Some((host, path)) = &self.client_target {
+            let key = handshake::generate_key();
+
+            if let Err(err) = (&channel).write_all(&handshake::upgrade_request(host, path, &key)) {
+                error!("Failed to send websocket upgrade request to {:?}: {:?}", channel.network().addr(), err);
+            }
+
+            Some(key)
+        } else {
+            None
+        };
+
+        let session = Session::new(self.role, client_key, self.config.max_message_size);
+
+        channel.attributes().insert(&SESSION, Mutex::new(session));
+
+        channel
+    }
+
+    fn handle_message_received(&self, channel: Arc<Channel>, buf: Vec<u8>) {
+        let session = self.session(&channel);
+
+        let mut session = session.lock_safe();
+
+        if let SessionState::Closed(_) = session.state {
+            return;
+        }
+
+        session.buffer.extend_from_slice(&buf);
+
+        channel.release_buffer(buf);
+
+        if let Err(err) = self.process(&channel, &mut session) {
+            debug!("Closing websocket connection {} because {:?}", channel.id(), err);
+
+            session.mark_closed();
+
+            channel.close();
+        }
+    }
+
+    fn handle_connection_removed(&self, channel: Arc<Channel>, _err: Option<RusttyError>) {
+        //The session holds on to the channel once open, so it has to be removed for the channel to be dropped
+        let session = channel.attributes().remove(&SESSION);
+
+        if let Some(session) = session {
+            let session = session.lock_safe();
+
+            match &session.state {
+                SessionState::Open(socket) | SessionState::Closed(Some(socket)) => {
+                    self.handler.handle_close(socket, session.close_code);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Session {
+    fn new(role: Role, client_key: Option<String>, max_message_size: usize) -> Self {
+        Session {
+            state: SessionState::Handshake(client_key),
+            buffer: Vec::new(),
+            codec: FrameCodec::new(role, max_message_size),
+            fragmented: None,
+            close_code: None,
+        }
+    }
+
+    fn mark_closed(&mut self) {
+        let previous = std::mem::replace(&mut self.state, SessionState::Closed(None));
+
+        if let SessionState::Open(socket) = previous {
+            self.state = SessionState::Closed(Some(socket));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+    use crossbeam_channel::{Receiver, Sender};
+    use crate::channel::ChannelNetwork;
+    use crate::config::BaseConfig;
+    use crate::event_group::EventGroup;
+    use super::*;
+
+    struct RecordingHandler {
+        messages: Sender<Message>,
+        closed: Sender<Option<u16>>,
+    }
+
+    impl WebSocketMessageHandler for RecordingHandler {
+        fn handle_message(&self, _socket: &WebSocket, message: Message) {
+            let _ = self.messages.send(message);
+        }
+
+        fn handle_close(&self, _socket: &WebSocket, code: Option<u16>) {
+            let _ = self.closed.send(code);
+        }
+    }
+
+    //The client side of a connection to a websocket server, speaking raw frames
+    struct Peer {
+        stream: TcpStream,
+        codec: FrameCodec,
+        buffer: Vec<u8>,
+    }
+
+    impl Peer {
+        fn send(&mut self, frame: Frame) {
+            let mut buf = Vec::new();
+
+            FrameCodec::new(Role::Client, usize::MAX).encode(frame, &mut buf).unwrap();
+
+            self.stream.write_all(&buf).unwrap();
+        }
+
+        fn receive(&mut self) -> Frame {
+            loop {
+                if let Some(frame) = self.codec.decode(&mut self.buffer).unwrap() {
+                    return frame;
+                }
+
+                self.read_more();
+            }
+        }
+
+        fn read_more(&mut self) -> usize {
+            let mut buf = [0; 1024];
+
+            let read = self.stream.read(&mut buf).unwrap();
+
+            self.buffer.extend_from_slice(&buf[..read]);
+
+            read
+        }
+
+        //Expect the server to close the connection with the given code
+        fn expect_close(&mut self, code: u16) {
+            let frame = self.receive();
+
+            assert_eq!(frame.op_code(), OpCode::Close);
+            assert_eq!(frame.payload()[..2], code.to_be_bytes());
+
+            assert_eq!(self.read_more(), 0, "The connection should be closed after the close frame");
+        }
+    }
+
+    //Connect to a websocket server that records what it receives, completing the upgrade handshake
+    fn connect(max_message_size: usize) -> (Peer, Receiver<Message>, Receiver<Option<u16>>) {
+        let (messages_tx, messages) = crossbeam_channel::unbounded();
+        let (closed_tx, closed) = crossbeam_channel::unbounded();
+
+        let handler = WebSocketHandler::server(RecordingHandler { messages: messages_tx, closed: closed_tx }, WebSocketConfig::new(max_message_size));
+
+        let handle = EventGroup::initialize_event_group(0, &BaseConfig::new(1, 1024), Arc::new(handler)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let (conn, addr) = listener.accept().unwrap();
+        conn.set_nonblocking(true).unwrap();
+
+        handle.register_new_connection(Channel::new(1, ChannelNetwork::new(addr, Box::new(conn), 1024), handle.clone())).unwrap();
+
+        let mut peer = Peer { stream, codec: FrameCodec::new(Role::Client, usize::MAX), buffer: Vec::new() };
+
+        let key = handshake::generate_key();
+
+        peer.stream.write_all(&handshake::upgrade_request("localhost", "/", &key)).unwrap();
+
+        loop {
+            if let HandshakeResult::Complete((), len) = handshake::parse_upgrade_response(&peer.buffer, &key).unwrap() {
+                peer.buffer.drain(..len);
+
+                return (peer, messages, closed);
+            }
+
+            peer.read_more();
+        }
+    }
+
+    #[test]
+    fn fragmented_messages_are_reassembled() {
+        let (mut peer, messages, _closed) = connect(1024);
+
+        peer.send(Frame::new(false, OpCode::Text, b"Hel".to_vec()));
+        //Control frames can arrive in between the fragments
+        peer.send(Frame::new(true, OpCode::Ping, b"ping".to_vec()));
+        peer.send(Frame::new(false, OpCode::Continuation, b"lo ".to_vec()));
+        peer.send(Frame::new(true, OpCode::Continuation, b"there".to_vec()));
+
+        let pong = peer.receive();
+
+        assert_eq!(pong.op_code(), OpCode::Pong);
+        assert_eq!(pong.payload(), b"ping");
+
+        assert_eq!(messages.recv_timeout(Duration::from_secs(5)).unwrap(), Message::Text("Hello there".to_string()));
+        assert!(messages.try_recv().is_err());
+    }
+
+    #[test]
+    fn close_frames_are_echoed_before_closing() {
+        let (mut peer, _messages, closed) = connect(1024);
+
+        peer.send(Frame::new(true, OpCode::Close, 1001u16.to_be_bytes().to_vec()));
+
+        peer.expect_close(1001);
+
+        assert_eq!(closed.recv_timeout(Duration::from_secs(5)).unwrap(), Some(1001));
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let (mut peer, messages, _closed) = connect(16);
+
+        peer.send(Frame::new(true, OpCode::Binary, vec![1; 32]));
+
+        peer.expect_close(CLOSE_MESSAGE_TOO_BIG);
+
+        //Each fragment fits, but the message doesn't
+        let (mut peer, _messages, _closed) = connect(16);
+
+        peer.send(Frame::new(false, OpCode::Binary, vec![1; 10]));
+        peer.send(Frame::new(true, OpCode::Continuation, vec![1; 10]));
+
+        peer.expect_close(CLOSE_MESSAGE_TOO_BIG);
+
+        assert!(messages.try_recv().is_err());
+    }
+
+    #[test]
+    fn invalid_text_is_rejected() {
+        let (mut peer, messages, _closed) = connect(1024);
+
+        peer.send(Frame::new(true, OpCode::Text, vec![0xff, 0xfe]));
+
+        peer.expect_close(CLOSE_INVALID_PAYLOAD);
+
+        assert!(messages.try_recv().is_err());
+    }
+}
@@ -1,4 +1,6 @@
 use std::net::IpAddr;
+use crate::channel::id::ChannelIdScheme;
+use crate::server::ip_filter::IpFilter;
 
 /// The base configuration, common to both servers and clients
 pub struct BaseConfig {
@@ -7,6 +9,63 @@ pub struct BaseConfig {
 
     /// The default size of the pending tx vector
     pending_tx_base_vec_size: usize,
+
+    /// How the ids of the channels are generated
+    channel_id_scheme: ChannelIdScheme,
+
+    /// The limits on the throughput of the channels
+    traffic_shaping: TrafficShaping,
+
+    /// How the channels are registered in the poller
+    poll_mode: PollMode,
+
+    /// How many buffers of each size class the buffer pool of an event group keeps
+    buffer_pool_capacity: usize,
+
+    /// How much we read from the channels when they become readable
+    read_sizing: ReadSizing,
+
+    /// How much we do for a channel on each event, before giving the other channels a turn
+    event_budget: EventBudget,
+
+    /// How many messages (new connections, closes, ...) can wait for the event loop
+    control_queue_capacity: usize,
+
+    /// How many events can wait for each worker of the event group
+    work_queue_capacity: usize,
+
+    /// What to do when those queues are full
+    overload_policy: OverloadPolicy,
+}
+
+/// How channels are registered in the poller of their event group
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PollMode {
+    /// We are notified of a single event, after which the channel has to be re-armed.
+    /// Costs a syscall per event, but the channel is never handled by more than one
+    /// thread at a time
+    #[default]
+    Oneshot,
+    /// We are notified for as long as the channel is readable (or writable)
+    Level,
+    /// We are only notified when the channel becomes readable (or writable),
+    /// so every event has to be handled until the socket would block
+    Edge,
+}
+
+/// What to do when the queues of an event group are full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    /// Wait for room in the queue. The event loop slows down to the pace of the workers,
+    /// and whoever hands new connections to the event loop slows down to its pace
+    #[default]
+    Block,
+    /// Drop the newest event, counting it in the overload stats. We stop listening to the
+    /// channel for a short while, after which its event is reported again.
+    /// New connections that don't fit are closed, while the server keeps accepting
+    DropNewest,
+    /// Close the channels whose events don't fit, along with the new connections that don't fit
+    Shed,
 }
 
 /// Communication that is related to the server, in conjunction with the base configurations
@@ -14,9 +73,99 @@ pub struct ServerConfig {
     base_config: BaseConfig,
     bind_addr: IpAddr,
     port: u16,
+
+    /// How many connections we are willing to accept
+    connection_limits: ConnectionLimits,
+
+    /// Which peers are allowed to connect.
+    /// Can be replaced at runtime through the server handle
+    ip_filter: IpFilter,
+}
+
+/// Limits on the amount of connections a server keeps at the same time
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionLimits {
+    /// The maximum amount of connections, in total
+    max_connections: Option<usize>,
+    /// The maximum amount of connections from a single IP address
+    max_connections_per_ip: Option<usize>,
+    /// What to do with connections that exceed the limits
+    policy: LimitPolicy,
+}
+
+/// What to do when a connection exceeds the connection limits
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Close the connection right away
+    #[default]
+    Reject,
+    /// Try to send the payload to the peer (for example, an error message in the
+    /// protocol being used) and then close the connection
+    RejectWithPayload(Vec<u8>),
+    /// Stop accepting connections until we are below the total limit again, leaving new
+    /// connections waiting in the listen backlog.
+    /// Connections that exceed the per IP limit are still rejected
+    PauseAccepting,
+}
+
+/// A limit on throughput, enforced with a token bucket.
+/// The bucket holds up to `burst` bytes and is refilled at `bytes_per_second`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    bytes_per_second: u64,
+    burst: u64,
+}
+
+/// How much we read from a channel when it becomes readable.
+/// The size of the buffers we read into adapts to each channel, between `minimum` and `maximum`:
+/// it grows when the reads fill it and shrinks when they are consistently much smaller
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadSizing {
+    minimum: usize,
+    initial: usize,
+    maximum: usize,
+}
+
+/// How much we do for a channel each time it's ready, so a single busy channel can't
+/// starve the other channels handled by the same worker.
+/// Once a budget runs out, the channel is queued again behind the other ready channels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventBudget {
+    /// The most bytes we read from a channel for each readable event
+    read_bytes: usize,
+    /// The most reads from the socket for each readable event
+    read_iterations: usize,
+    /// The most bytes we write to a channel for each writable event
+    write_bytes: usize,
+    /// The most writes to the socket for each writable event
+    write_iterations: usize,
+}
+
+/// The throughput limits applied to the channels of an event group.
+/// The channel limits apply to each channel individually, while the group limits
+/// are shared by all of the channels of the event group
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrafficShaping {
+    channel_read: Option<RateLimit>,
+    channel_write: Option<RateLimit>,
+    group_read: Option<RateLimit>,
+    group_write: Option<RateLimit>,
 }
 
 impl ServerConfig {
+    pub fn new(base_config: BaseConfig, bind_addr: IpAddr, port: u16) -> Self {
+        ServerConfig {
+            base_config,
+            bind_addr,
+            port,
+            connection_limits: ConnectionLimits::default(),
+            ip_filter: IpFilter::allow_all(),
+        }
+    }
+
+    pub fn set_connection_limits(&mut self, connection_limits: ConnectionLimits) {
+        self.connection_limits = connection_limits;
+    }
 
     pub fn bind_addr(&self) -> IpAddr {
         self.bind_addr
@@ -29,9 +178,234 @@ impl ServerConfig {
     pub fn base_config(&self) -> &BaseConfig {
         &self.base_config
     }
+
+    pub fn connection_limits(&self) -> &ConnectionLimits {
+        &self.connection_limits
+    }
+
+    pub fn set_ip_filter(&mut self, ip_filter: IpFilter) {
+        self.ip_filter = ip_filter;
+    }
+
+    pub fn ip_filter(&self) -> &IpFilter {
+        &self.ip_filter
+    }
+}
+
+impl ConnectionLimits {
+    pub fn new(max_connections: Option<usize>, max_connections_per_ip: Option<usize>, policy: LimitPolicy) -> Self {
+        ConnectionLimits {
+            max_connections,
+            max_connections_per_ip,
+            policy,
+        }
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    pub fn max_connections_per_ip(&self) -> Option<usize> {
+        self.max_connections_per_ip
+    }
+
+    pub fn policy(&self) -> &LimitPolicy {
+        &self.policy
+    }
+}
+
+impl RateLimit {
+    /// A limit of 0 bytes per second is raised to 1, as the channels would never make progress
+    pub fn new(bytes_per_second: u64, burst: u64) -> Self {
+        RateLimit {
+            bytes_per_second: bytes_per_second.max(1),
+            //We must be able to hold at least a byte, or we would never make progress
+            burst: burst.max(1),
+        }
+    }
+
+    /// A limit that allows bursts of up to a second's worth of bytes
+    pub fn per_second(bytes_per_second: u64) -> Self {
+        Self::new(bytes_per_second, bytes_per_second)
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    pub fn burst(&self) -> u64 {
+        self.burst
+    }
+}
+
+impl ReadSizing {
+    /// The sizes are clamped so that `0 < minimum <= initial <= maximum`
+    pub fn new(minimum: usize, initial: usize, maximum: usize) -> Self {
+        let minimum = minimum.max(1);
+        let maximum = maximum.max(minimum);
+
+        ReadSizing {
+            minimum,
+            initial: initial.clamp(minimum, maximum),
+            maximum,
+        }
+    }
+
+    pub fn minimum(&self) -> usize {
+        self.minimum
+    }
+
+    pub fn initial(&self) -> usize {
+        self.initial
+    }
+
+    pub fn maximum(&self) -> usize {
+        self.maximum
+    }
+
+}
+
+impl Default for ReadSizing {
+    fn default() -> Self {
+        Self::new(64, 1024, 64 * 1024)
+    }
+}
+
+//The budgets must allow at least a byte and a single call, or we would never make progress
+impl EventBudget {
+    pub fn set_read_bytes(&mut self, read_bytes: usize) {
+        self.read_bytes = read_bytes.max(1);
+    }
+
+    pub fn set_read_iterations(&mut self, read_iterations: usize) {
+        self.read_iterations = read_iterations.max(1);
+    }
+
+    pub fn set_write_bytes(&mut self, write_bytes: usize) {
+        self.write_bytes = write_bytes.max(1);
+    }
+
+    pub fn set_write_iterations(&mut self, write_iterations: usize) {
+        self.write_iterations = write_iterations.max(1);
+    }
+
+    pub fn read_bytes(&self) -> usize {
+        self.read_bytes
+    }
+
+    pub fn read_iterations(&self) -> usize {
+        self.read_iterations
+    }
+
+    pub fn write_bytes(&self) -> usize {
+        self.write_bytes
+    }
+
+    pub fn write_iterations(&self) -> usize {
+        self.write_iterations
+    }
+}
+
+impl Default for EventBudget {
+    fn default() -> Self {
+        EventBudget {
+            read_bytes: 256 * 1024,
+            read_iterations: 16,
+            write_bytes: 256 * 1024,
+            write_iterations: 16,
+        }
+    }
+}
+
+impl TrafficShaping {
+    pub fn set_channel_read(&mut self, limit: Option<RateLimit>) {
+        self.channel_read = limit;
+    }
+
+    pub fn set_channel_write(&mut self, limit: Option<RateLimit>) {
+        self.channel_write = limit;
+    }
+
+    pub fn set_group_read(&mut self, limit: Option<RateLimit>) {
+        self.group_read = limit;
+    }
+
+    pub fn set_group_write(&mut self, limit: Option<RateLimit>) {
+        self.group_write = limit;
+    }
+
+    pub fn channel_read(&self) -> Option<RateLimit> {
+        self.channel_read
+    }
+
+    pub fn channel_write(&self) -> Option<RateLimit> {
+        self.channel_write
+    }
+
+    pub fn group_read(&self) -> Option<RateLimit> {
+        self.group_read
+    }
+
+    pub fn group_write(&self) -> Option<RateLimit> {
+        self.group_write
+    }
 }
 
 impl BaseConfig {
+    pub fn new(event_loop_thread_count: usize, pending_tx_base_vec_size: usize) -> Self {
+        BaseConfig {
+            event_loop_thread_count,
+            pending_tx_base_vec_size,
+            channel_id_scheme: ChannelIdScheme::default(),
+            traffic_shaping: TrafficShaping::default(),
+            poll_mode: PollMode::default(),
+            buffer_pool_capacity: 64,
+            read_sizing: ReadSizing::default(),
+            event_budget: EventBudget::default(),
+            control_queue_capacity: 1024,
+            work_queue_capacity: 1024,
+            overload_policy: OverloadPolicy::default(),
+        }
+    }
+
+    pub fn set_channel_id_scheme(&mut self, channel_id_scheme: ChannelIdScheme) {
+        self.channel_id_scheme = channel_id_scheme;
+    }
+
+    pub fn set_traffic_shaping(&mut self, traffic_shaping: TrafficShaping) {
+        self.traffic_shaping = traffic_shaping;
+    }
+
+    pub fn set_poll_mode(&mut self, poll_mode: PollMode) {
+        self.poll_mode = poll_mode;
+    }
+
+    /// Set how many buffers of each size class are kept for reuse. 0 disables pooling
+    pub fn set_buffer_pool_capacity(&mut self, buffer_pool_capacity: usize) {
+        self.buffer_pool_capacity = buffer_pool_capacity;
+    }
+
+    pub fn set_read_sizing(&mut self, read_sizing: ReadSizing) {
+        self.read_sizing = read_sizing;
+    }
+
+    pub fn set_event_budget(&mut self, event_budget: EventBudget) {
+        self.event_budget = event_budget;
+    }
+
+    //The queues must be able to hold at least a message, or nothing could be handed over without blocking
+    pub fn set_control_queue_capacity(&mut self, control_queue_capacity: usize) {
+        self.control_queue_capacity = control_queue_capacity.max(1);
+    }
+
+    pub fn set_work_queue_capacity(&mut self, work_queue_capacity: usize) {
+        self.work_queue_capacity = work_queue_capacity.max(1);
+    }
+
+    pub fn set_overload_policy(&mut self, overload_policy: OverloadPolicy) {
+        self.overload_policy = overload_policy;
+    }
+
     pub fn event_loop_thread_count(&self) -> usize {
         self.event_loop_thread_count
     }
@@ -39,4 +413,40 @@ impl BaseConfig {
     pub fn pending_tx_base_vec_size(&self) -> usize {
         self.pending_tx_base_vec_size
     }
+
+    pub fn channel_id_scheme(&self) -> ChannelIdScheme {
+        self.channel_id_scheme
+    }
+
+    pub fn traffic_shaping(&self) -> TrafficShaping {
+        self.traffic_shaping
+    }
+
+    pub fn poll_mode(&self) -> PollMode {
+        self.poll_mode
+    }
+
+    pub fn buffer_pool_capacity(&self) -> usize {
+        self.buffer_pool_capacity
+    }
+
+    pub fn read_sizing(&self) -> ReadSizing {
+        self.read_sizing
+    }
+
+    pub fn event_budget(&self) -> EventBudget {
+        self.event_budget
+    }
+
+    pub fn control_queue_capacity(&self) -> usize {
+        self.control_queue_capacity
+    }
+
+    pub fn work_queue_capacity(&self) -> usize {
+        self.work_queue_capacity
+    }
+
+    pub fn overload_policy(&self) -> OverloadPolicy {
+        self.overload_policy
+    }
 }
\ No newline at end of file
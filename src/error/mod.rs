@@ -0,0 +1,66 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+
+pub type Result<T> = std::result::Result<T, RusttyError>;
+
+/// The errors produced by Rustty
+#[derive(Debug)]
+pub enum RusttyError {
+    /// Failed to bind the listener to the configured address
+    Bind(io::Error),
+    /// The poller failed, either when creating it, waiting for events or
+    /// changing the events we are interested in
+    Poller(io::Error),
+    /// Failed to hand something over to an event group, as it's no longer running
+    Registration,
+    /// The channel has already been closed
+    ChannelClosed,
+    /// The event group is overloaded, so the channel was closed by its overload policy
+    Overloaded,
+    /// The peer did not follow the protocol we were expecting
+    Protocol(String),
+    /// Any other I/O error
+    Io(io::Error),
+}
+
+impl Display for RusttyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RusttyError::Bind(err) => write!(f, "Failed to bind the listener: {}", err),
+            RusttyError::Poller(err) => write!(f, "Poller failure: {}", err),
+            RusttyError::Registration => write!(f, "The event group is no longer running"),
+            RusttyError::ChannelClosed => write!(f, "The channel is closed"),
+            RusttyError::Overloaded => write!(f, "The event group is overloaded"),
+            RusttyError::Protocol(reason) => write!(f, "Protocol error: {}", reason),
+            RusttyError::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl Error for RusttyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RusttyError::Bind(err) | RusttyError::Poller(err) | RusttyError::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for RusttyError {
+    fn from(err: io::Error) -> Self {
+        RusttyError::Io(err)
+    }
+}
+
+/// So our errors can be returned from the [std::io] traits we implement
+impl From<RusttyError> for io::Error {
+    fn from(err: RusttyError) -> Self {
+        match err {
+            RusttyError::Bind(err) | RusttyError::Poller(err) | RusttyError::Io(err) => err,
+            RusttyError::Registration | RusttyError::ChannelClosed => io::Error::new(io::ErrorKind::NotConnected, err),
+            RusttyError::Overloaded => io::Error::new(io::ErrorKind::ConnectionAborted, err),
+            RusttyError::Protocol(_) => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
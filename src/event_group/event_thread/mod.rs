@@ -1,17 +1,31 @@
-use std::io::{ErrorKind, Read, Write};
+use std::io::{ErrorKind, Read};
 use std::sync::Arc;
 use std::sync::atomic::Ordering;
 use crossbeam_channel::Receiver;
+use log::debug;
 use polling::Event;
 
 use crate::channel::Channel;
-use crate::event_group::{EventGroupCommon, EventGroupHandle};
+use crate::error::RusttyError;
+use crate::event_group::EventGroupHandle;
+use crate::event_group::traffic::Direction;
+use crate::util::LockExt;
 
-type Work = IOWork;
+pub(super) type Work = IOWork;
 
-const READ_BUFFER_SIZE: usize = 1024;
+/// What is left to do for a channel after handling its event.
+/// Ordered so handling both directions of an event results in the most urgent one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Handled {
+    /// We handled everything the channel was ready for
+    Done,
+    /// We stopped early, so the channel might still be ready
+    Pending,
+    /// The channel has been closed
+    Closed,
+}
 
-struct IOWork {
+pub(super) struct IOWork {
     channel: Arc<Channel>,
     event: Event,
 }
@@ -23,14 +37,34 @@ pub struct EventGroupWorker {
     work_receiver: Receiver<Work>,
 }
 
+impl IOWork {
+    pub(super) fn new(channel: Arc<Channel>, event: Event) -> Self {
+        IOWork {
+            channel,
+            event,
+        }
+    }
+}
+
 impl EventGroupWorker {
+    pub(super) fn new(ev_group_worker_id: usize, ev_group_info: EventGroupHandle, work_receiver: Receiver<Work>) -> Self {
+        EventGroupWorker {
+            ev_group_worker_id,
+            ev_group_info,
+            work_receiver,
+        }
+    }
+
     pub fn begin(self) {
         loop {
             let (channel, event) = match self.work_receiver.recv() {
                 Ok(Work { channel, event }) => {
                     (channel, event)
                 }
-                Err(err) => {
+                Err(_) => {
+                    //The event loop is gone, so there will be no more work
+                    debug!("Stopping event group worker #{}", self.ev_group_worker_id);
+
                     break;
                 }
             };
@@ -41,33 +75,130 @@ impl EventGroupWorker {
 
     /// Handle an event, received from the epoll layer
     fn handle_event(&self, ev: Event, channel: &Arc<Channel>) {
+        let mut handled = Handled::Done;
+
         if ev.readable {
-            self.handle_ev_readable(channel);
+            handled = self.handle_ev_readable(channel);
+        }
+
+        if handled != Handled::Closed && ev.writable {
+            handled = handled.max(self.handle_ev_writable(channel));
+        }
+
+        if handled == Handled::Closed {
+            return;
         }
 
-        if ev.writable {
-            self.handle_ev_writable(channel);
+        //If we stopped before the socket would block, there is no new readiness to notify
+        //us about in edge triggered mode, so re-arming makes the poller check it again.
+        //In oneshot mode we are only notified once for each registration, so we always re-arm
+        if let Err(err) = self.ev_group_info.finish_event(channel, handled == Handled::Pending) {
+            let _ = self.ev_group_info.close_connection(channel, Some(err));
         }
     }
 
-    /// handle a readable event
-    fn handle_ev_readable(&self, channel: &Arc<Channel>) {
-        let read = {
-            let mut final_buffer = Vec::with_capacity(READ_BUFFER_SIZE);
+    /// handle a readable event.
+    /// Reads into buffers sized for this channel, delivering each one as it fills up,
+    /// until the socket would block or we have used up the budget of this event
+    fn handle_ev_readable(&self, channel: &Arc<Channel>) -> Handled {
+        if !channel.is_auto_read() {
+            //Reading was disabled after this event was produced
+            return Handled::Done;
+        }
+
+        //How many bytes we can read without exceeding the read rate
+        let mut allowance = channel.traffic().allowance(Direction::Read);
+
+        if allowance == Some(0) {
+            //Stop listening to this channel until we are allowed to read from it again
+            //If this fails, the event loop has stopped and the channel will not be polled again anyway
+            let _ = self.ev_group_info.throttle_reads(channel, channel.traffic().delay(Direction::Read));
+
+            return Handled::Done;
+        }
+
+        let read_size = channel.read_size();
+
+        let budget = self.ev_group_info.event_budget();
+
+        let max_bytes = budget.read_bytes();
 
-            let mut read_buffer = Vec::with_capacity(READ_BUFFER_SIZE);
+        let mut reads = 0;
 
-            let mut socket = channel.network().socket().lock().unwrap();
+        //Set when the connection has to be closed, along with the error that caused it
+        let mut closed = None;
+
+        //Whether we stopped reading before the socket would block
+        let mut pending = false;
+
+        let mut total = 0;
+
+        let pool = self.ev_group_info.buffer_pool();
+
+        let messages = {
+            let mut messages = Vec::new();
+
+            //We read straight into a pooled buffer, growing it as needed.
+            //The buffers are handed over to the handler as they are, without being copied
+            let mut read_buffer = pool.lease(read_size.next_size());
+
+            read_buffer.resize(read_size.next_size(), 0);
+
+            let mut filled = 0;
+
+            let mut socket = channel.network().socket().lock_safe();
 
             loop {
-                let result = socket.read(&mut read_buffer[..]);
+                if filled == read_buffer.len() {
+                    if read_buffer.len() < read_size.sizing().maximum() {
+                        read_buffer.resize((read_buffer.len() * 2).min(read_size.sizing().maximum()), 0);
+                    } else {
+                        //The handler takes ownership of the full buffer, so we keep reading into a new one
+                        let full = std::mem::replace(&mut read_buffer, pool.lease(filled));
+
+                        read_buffer.resize(full.len(), 0);
+
+                        messages.push(full);
+
+                        filled = 0;
+                    }
+                }
+
+                if total >= max_bytes || reads >= budget.read_iterations() {
+                    //Let the other channels of this worker have their turn
+                    pending = true;
+
+                    break;
+                }
+
+                let space = (read_buffer.len() - filled).min(max_bytes - total);
+
+                let to_read = allowance.map_or(space, |allowance| space.min(allowance as usize));
+
+                if to_read == 0 {
+                    //We have used up all of our allowance, so there might still be more to read
+                    let _ = self.ev_group_info.throttle_reads(channel, channel.traffic().delay(Direction::Read));
+
+                    break;
+                }
+
+                reads += 1;
+
+                let result = socket.read(&mut read_buffer[filled..filled + to_read]);
 
                 match result {
                     Ok(read_bytes) => {
                         if read_bytes > 0 {
-                            final_buffer.append(&mut read_buffer);
+                            filled += read_bytes;
+                            total += read_bytes;
+
+                            channel.traffic().consume(Direction::Read, read_bytes);
+
+                            allowance = allowance.map(|allowance| allowance - read_bytes as u64);
                         } else {
-                            //We did not read anything, so there must be nothing to read
+                            //The peer has closed the connection
+                            closed = Some(None);
+
                             break;
                         }
                     }
@@ -77,84 +208,93 @@ impl EventGroupWorker {
                                 //We are done reading until the next IO event
                                 break;
                             }
+                            ErrorKind::Interrupted => {
+                                continue;
+                            }
                             _ => {
-                                //TODO: This means the socket has failed and as such must be disconnected
+                                //This means the socket has failed and as such must be disconnected
+                                closed = Some(Some(RusttyError::Io(err)));
+
+                                break;
                             }
                         }
                     }
                 }
             }
 
-            final_buffer
+            if filled > 0 {
+                read_buffer.truncate(filled);
+
+                messages.push(read_buffer);
+            } else {
+                pool.release(read_buffer);
+            }
+
+            messages
         };
 
-        //TODO: deliver the read message to the client
+        read_size.record(total);
+
+        //Deliver what we read before the connection was closed, if anything
+        for message in messages {
+            self.ev_group_info.handler().handle_message_received(channel.clone(), message);
+        }
+
+        if let Some(err) = closed {
+            let _ = self.ev_group_info.close_connection(channel, err);
+
+            return Handled::Closed;
+        }
+
+        if pending {
+            Handled::Pending
+        } else {
+            Handled::Done
+        }
     }
 
-    /// Handle a writable event
-    fn handle_ev_writable(&self, channel: &Arc<Channel>) {
+    /// Handle a writable event.
+    /// Writes the pending bytes until the socket would block, or until we have used up
+    /// the budget of this event or the write rate of the channel
+    fn handle_ev_writable(&self, channel: &Arc<Channel>) -> Handled {
         //If we are receiving this event, this means that we have attempt to perform a send
-        //That would block, and as such we had to wait for the epoll event
-        let vec = channel.network().take_pending_tx();
-
-        if let Some(pending_tx) = vec {
-            //Acquire the lock to the socket, so that we write this in the correct order
-
-            let mut pending_tx_slice = &pending_tx[..];
-            let mut bytes_to_write = pending_tx_slice.len();
-
-            let mut error = None;
-
-            let remaining_bytes = {
-                let mut socket = channel.network().socket().lock().unwrap();
-
-                loop {
-                    //Attempt to send all of the bytes in our pending_tx buffer.
-                    //We always keep the lock since this write method does not block
-                    //And as such this should be quite quick.
-                    match socket.write(pending_tx_slice) {
-                        Ok(bytes_written) => {
-                            if bytes_written > 0 && bytes_written == bytes_to_write {
-                                break None;
-                            } else if bytes_written > 0 {
-                                //Take the bytes that were not yet written
-                                pending_tx_slice = &pending_tx_slice[bytes_written..];
-
-                                bytes_to_write -= bytes_written;
-                            } else {
-                                //What?
-                                break Some(pending_tx_slice);
-                            }
-                        }
-                        Err(err) => {
-                            match err.kind() {
-                                ErrorKind::WouldBlock => {
-                                    break Some(pending_tx_slice);
-                                }
-                                _ => {
-                                    error = Some(err);
-
-                                    break Some(pending_tx_slice);
-                                }
-                            }
-                        }
-                    }
-                }
-                //Return the remaining bytes that were not sent so they can be added to the
-                //pending_tx buffer again. They must be added at the beginning so that
-                //We maintain request ordering of the applications
-            };
+        //That would block, and as such we had to wait for the epoll event.
+        //Bytes queued while we were writing are written as well, so they are not left behind
+        //(which edge triggered polling requires). If we stop before the socket would block,
+        //the channel is re-armed so we get back to it
+        let budget = self.ev_group_info.event_budget();
 
-            if let Some(err) = error {
-                //TODO: Disconnect connection
-                self.ev_group_info.close_connection(channel, Some(err))
-            } else {
-                if let Some(remaining_bytes) = remaining_bytes {
-                    channel.network().begin_extend_from_slice(remaining_bytes);
-                    //Register that we still have some more things to write to the socket
-                    self.ev_group_info.register_write_intention(channel);
-                }
-            }
+        //Only write as much as the write rate allows, leaving the rest for later
+        let allowance = channel.traffic().allowance(Direction::Write)
+            .map_or(usize::MAX, |allowance| allowance as usize);
+
+        let flushed = channel.network().write_pending_tx(allowance.min(budget.write_bytes()),
+                                                         budget.write_iterations(),
+                                                         self.ev_group_info.buffer_pool());
+
+        channel.traffic().consume(Direction::Write, flushed.written);
+
+        //Let anyone waiting for these bytes know they have been sent
+        channel.network().report_flushed(flushed.written);
+
+        if let Some(err) = flushed.error {
+            let _ = self.ev_group_info.close_connection(channel, Some(RusttyError::Io(err)));
+
+            return Handled::Closed;
+        }
+
+        if flushed.would_block || !channel.network().has_pending_tx().load(Ordering::SeqCst) {
+            return Handled::Done;
         }
+
+        if flushed.written >= allowance {
+            //Wait until we are allowed to write again. The remaining bytes stay queued
+            let _ = self.ev_group_info.throttle_writes(channel, channel.traffic().delay(Direction::Write));
+
+            return Handled::Done;
+        }
+
+        //We have used up the budget of this event
+        Handled::Pending
     }
-}
\ No newline at end of file
+}
@@ -1,27 +1,69 @@
-use std::collections::BTreeMap;
-use std::error::Error;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use crossbeam_channel::{Receiver, Sender};
-use log::error;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::Ordering;
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{Receiver, Select, SendError, Sender, TrySendError};
+use log::{debug, error, warn};
 use polling::{Event, Poller};
 use crate::channel::Channel;
+use crate::config::{BaseConfig, EventBudget, OverloadPolicy, PollMode, ReadSizing};
+use crate::error::{Result, RusttyError};
+use crate::event_group::event_thread::{EventGroupWorker, IOWork};
+use crate::event_group::overload::{OverloadCounters, OverloadStats};
+use crate::event_group::traffic::{Direction, GroupTraffic};
+use crate::util::{ChannelHandler, LockExt};
+use crate::util::pool::BufferPool;
 
 mod event_thread;
+pub(crate) mod overload;
+pub(crate) mod read_size;
+pub(crate) mod traffic;
 
+thread_local! {
+    //The messages the event loop sends to itself, from the handlers it calls.
+    //It can't wait for room in its own queue, so these are kept aside until it gets to them
+    static DEFERRED_MESSAGES: RefCell<VecDeque<EventGroupMessage>> = const { RefCell::new(VecDeque::new()) };
+}
+
+#[derive(Clone)]
 pub struct EventGroupHandle {
-    tx: Sender<EventGroupMessage>
+    tx: Sender<EventGroupMessage>,
+    traffic: Arc<GroupTraffic>,
+    buffer_pool: Arc<BufferPool>,
+    read_sizing: ReadSizing,
+    event_budget: EventBudget,
+    handler: Arc<dyn ChannelHandler>,
+    poller: Arc<Poller>,
+    poll_mode: PollMode,
+    overload_policy: OverloadPolicy,
+    overload: Arc<OverloadCounters>,
+    //The thread running the event loop, set once it starts
+    event_loop_thread: Arc<OnceLock<ThreadId>>,
 }
 
 /// Messages to communicate with the event group
 pub enum EventGroupMessage {
     AddConnection(Arc<Channel>),
-    RemoveConnection(usize)
+    /// Remove the channel, along with the error that caused it to be removed (if any)
+    RemoveConnection(usize, Option<RusttyError>),
+    /// Stop reading from the channel until the given instant, as it has exceeded its read rate
+    ThrottleReads(usize, Instant),
+    /// Stop writing to the channel until the given instant, as it has exceeded its write rate
+    ThrottleWrites(usize, Instant),
 }
 
-struct EventGroupCommon {
-    currently_connected: Mutex<BTreeMap<usize, Arc<Channel>>>,
-    poller: Arc<Poller>
+/// How a channel is registered in the poller
+#[derive(Default)]
+pub(crate) struct Registration {
+    //The (readable, writable) interest the channel is registered with
+    interest: (bool, bool),
+    //Whether an event of the channel has been handed to its worker and not handled yet.
+    //The registration is left alone in the meantime, the worker updates it once it's done
+    in_flight: bool,
+    //Whether the channel was reported, or its interest changed, while its event was in flight
+    missed: bool,
 }
 
 /// The event group for a given server
@@ -30,42 +72,114 @@ pub struct EventGroup {
     ev_loop_id: usize,
     event_messages: Receiver<EventGroupMessage>,
     currently_connected: BTreeMap<usize, Arc<Channel>>,
+    //The channels whose reads and writes are paused because of their rate limits,
+    //along with when they can be resumed
+    throttled_reads: BTreeMap<usize, Instant>,
+    throttled_writes: BTreeMap<usize, Instant>,
+    //The channels whose events were dropped because of the overload policy,
+    //along with when we start listening to them again
+    backed_off: BTreeMap<usize, Instant>,
     workers: EventGroupWorkers,
-    poller: Poller
+    handler: Arc<dyn ChannelHandler>,
+    poller: Arc<Poller>,
+    poll_mode: PollMode,
+    overload_policy: OverloadPolicy,
+    overload: Arc<OverloadCounters>,
+    event_loop_thread: Arc<OnceLock<ThreadId>>,
 }
 
 /// The workers for an event group
-/// To load balance, we use a simple round robin approach
-struct EventGroupWorkers;
+/// To load balance, we spread the channels across the workers by their id.
+/// This way, the events of a channel are always handled in order, by the same worker
+struct EventGroupWorkers {
+    workers: Vec<Sender<IOWork>>,
+}
 
 const EVENT_LIMIT: usize = 1024;
 
+/// How long we stop listening to a channel whose event was dropped, as its worker is overloaded
+const OVERLOAD_BACKOFF: Duration = Duration::from_millis(10);
+
 impl EventGroup {
 
-    pub fn initialize_event_group(event_loop_id: usize, thread_count: usize) -> EventGroupHandle {
-        let (comm_tx, comm_rx) = crossbeam_channel::bounded(1024);
+    pub fn initialize_event_group(event_loop_id: usize, config: &BaseConfig, handler: Arc<dyn ChannelHandler>) -> Result<EventGroupHandle> {
+        let (comm_tx, comm_rx) = crossbeam_channel::bounded(config.control_queue_capacity());
+
+        let poller = Arc::new(Poller::new().map_err(RusttyError::Poller)?);
+
+        let poll_mode = config.poll_mode();
+
+        let supported = match poll_mode {
+            PollMode::Oneshot => true,
+            PollMode::Level => poller.supports_level(),
+            PollMode::Edge => poller.supports_edge(),
+        };
+
+        if !supported {
+            return Err(RusttyError::Poller(std::io::Error::new(std::io::ErrorKind::Unsupported,
+                                                               format!("{:?} polling is not supported on this platform", poll_mode))));
+        }
+
+        let thread_count = config.event_loop_thread_count();
+
+        let handle = EventGroupHandle {
+            tx: comm_tx,
+            traffic: Arc::new(GroupTraffic::new(config.traffic_shaping())),
+            buffer_pool: Arc::new(BufferPool::new(config.buffer_pool_capacity())),
+            read_sizing: config.read_sizing(),
+            event_budget: config.event_budget(),
+            handler: handler.clone(),
+            poller: poller.clone(),
+            poll_mode,
+            overload_policy: config.overload_policy(),
+            overload: Arc::new(OverloadCounters::default()),
+            event_loop_thread: Arc::new(OnceLock::new()),
+        };
 
-        let poller = Poller::new().unwrap();
+        let mut workers = Vec::with_capacity(thread_count);
+
+        //We always need at least one worker to handle the events
+        for worker_id in 0..thread_count.max(1) {
+            let (work_tx, work_rx) = crossbeam_channel::bounded(config.work_queue_capacity());
+
+            let worker = EventGroupWorker::new(worker_id, handle.clone(), work_rx);
+
+            std::thread::Builder::new()
+                .name(format!("Event loop #{} worker #{}", event_loop_id, worker_id))
+                .spawn(move || worker.begin())?;
+
+            workers.push(work_tx);
+        }
 
         let ev_group = EventGroup {
             ev_loop_id: event_loop_id,
             event_messages: comm_rx,
             currently_connected: Default::default(),
-            workers: EventGroupWorkers {},
-            poller
+            throttled_reads: Default::default(),
+            throttled_writes: Default::default(),
+            backed_off: Default::default(),
+            workers: EventGroupWorkers {
+                workers,
+            },
+            handler,
+            poller,
+            poll_mode,
+            overload_policy: handle.overload_policy,
+            overload: handle.overload.clone(),
+            event_loop_thread: handle.event_loop_thread.clone(),
         };
 
-        ev_group.begin();
+        ev_group.begin()?;
 
-        EventGroupHandle {
-            tx: comm_tx
-        }
+        Ok(handle)
     }
 
-    fn begin(mut self) {
+    fn begin(mut self) -> Result<()> {
         std::thread::Builder::new()
             .name(format!("Event loop thread #{}", self.ev_loop_id))
             .spawn(move || {
+                let _ = self.event_loop_thread.set(std::thread::current().id());
+
                 let mut events = Vec::with_capacity(EVENT_LIMIT);
 
                 loop {
@@ -99,74 +213,1005 @@ impl EventGroup {
 
                             if !ev.writable && !ev.readable {
                                 //This means the connection must have suffered some sort of issue.
-                                self.remove_connection(channel_id);
+                                self.remove_connection(channel_id, None);
 
                                 continue
                             }
 
+                            if let Some(channel) = self.currently_connected.get(&channel_id) {
+                                self.dispatch(channel.clone(), *ev);
+                            }
                         }
-
-                        self.workers.deliver_io_work(events);
-                        //Restate the variable, since we just disposed of the previous value
-                        events = Vec::with_capacity(EVENT_LIMIT);
                     }
 
                     //Listen to any messages intended for the event group, such as new connections
                     //Or connection close attempts
-                    while let Ok(channel) = self.event_messages.try_recv() {
-                        match channel {
-                            EventGroupMessage::AddConnection(channel) => {
-                                self.add_connection(channel);
-                            }
-                            EventGroupMessage::RemoveConnection(channel_id) => {
-                                self.remove_connection(channel_id);
-                            }
-                        }
+                    while let Ok(message) = self.event_messages.try_recv() {
+                        self.handle_message(message);
+                    }
 
+                    while let Some(message) = DEFERRED_MESSAGES.with(|deferred| deferred.borrow_mut().pop_front()) {
+                        self.handle_message(message);
                     }
+
+                    self.resume_throttled(Instant::now());
+                }
+            })?;
+
+        Ok(())
+    }
+
+
+    fn handle_message(&mut self, message: EventGroupMessage) {
+        match message {
+            EventGroupMessage::AddConnection(channel) => {
+                self.add_connection(channel);
+            }
+            EventGroupMessage::RemoveConnection(channel_id, err) => {
+                self.remove_connection(channel_id, err);
+            }
+            //The worker that throttled the channel has already removed the interest,
+            //we just have to resume it once the time is up
+            EventGroupMessage::ThrottleReads(channel_id, until) => {
+                self.throttled_reads.insert(channel_id, until);
+            }
+            EventGroupMessage::ThrottleWrites(channel_id, until) => {
+                self.throttled_writes.insert(channel_id, until);
+            }
+        }
+    }
+
+    /// Hand the event over to the worker of the channel, applying the overload policy
+    /// if its queue is full
+    fn dispatch(&mut self, channel: Arc<Channel>, ev: Event) {
+        match self.begin_dispatch(&channel) {
+            Ok(true) => {}
+            //Its worker will check the channel again once it's done with the previous event
+            Ok(false) => return,
+            Err(err) => {
+                self.remove_connection(channel.id(), Some(err));
+
+                return;
+            }
+        }
+
+        let worker = self.workers.worker_for(channel.id()).clone();
+
+        let work = match worker.try_send(IOWork::new(channel.clone(), ev)) {
+            Ok(()) => return,
+            Err(TrySendError::Disconnected(_)) => {
+                error!("Failed to deliver work to event group worker, as it is no longer running");
+
+                return;
+            }
+            Err(TrySendError::Full(work)) => work,
+        };
+
+        match self.overload_policy {
+            OverloadPolicy::Block => self.wait_for_worker(&worker, work),
+            OverloadPolicy::DropNewest => {
+                self.overload.event_dropped();
+
+                //Give the worker some time to catch up before the poller reports the channel again,
+                //so the event is not lost for good and we don't keep dropping it right away
+                if let Err(err) = self.back_off(&channel) {
+                    self.remove_connection(channel.id(), Some(err));
                 }
-            }).expect("Failed to launch event loop thread");
+            }
+            OverloadPolicy::Shed => {
+                warn!("Closing channel {}, as its worker can't keep up", channel.id());
+
+                self.overload.connection_shed();
+
+                self.remove_connection(channel.id(), Some(RusttyError::Overloaded));
+            }
+        }
     }
 
+    /// Mark the channel as having an event in flight, unless it already has one.
+    /// Level triggered channels keep being reported while their event is in flight, so we
+    /// stop listening to them until their worker is done, which restores their interest
+    fn begin_dispatch(&self, channel: &Channel) -> Result<bool> {
+        let mut registration = channel.network().registration().lock_safe();
+
+        if !registration.in_flight {
+            registration.in_flight = true;
+
+            return Ok(true);
+        }
+
+        registration.missed = true;
+
+        if self.poll_mode == PollMode::Level && registration.interest != (false, false) {
+            let none = Event { key: channel.id(), readable: false, writable: false };
+
+            apply_interest(&self.poller, self.poll_mode, channel, &mut registration, none)?;
+        }
+
+        Ok(false)
+    }
+
+    /// Stop listening to the channel until the overload backoff has passed
+    fn back_off(&mut self, channel: &Channel) -> Result<()> {
+        let mut registration = channel.network().registration().lock_safe();
+
+        registration.in_flight = false;
+        registration.missed = false;
+
+        let none = Event { key: channel.id(), readable: false, writable: false };
+
+        apply_interest(&self.poller, self.poll_mode, channel, &mut registration, none)?;
+
+        self.backed_off.insert(channel.id(), Instant::now() + OVERLOAD_BACKOFF);
+
+        Ok(())
+    }
+
+    /// Wait for room in the queue of the worker.
+    /// We keep handling our messages in the meantime, as the worker might be waiting
+    /// for room in our queue to hand us one
+    fn wait_for_worker(&mut self, worker: &Sender<IOWork>, work: IOWork) {
+        let messages = self.event_messages.clone();
+
+        loop {
+            let mut select = Select::new();
+
+            let send_index = select.send(worker);
+            select.recv(&messages);
+
+            let operation = select.select();
+
+            if operation.index() == send_index {
+                if operation.send(worker, work).is_err() {
+                    error!("Failed to deliver work to event group worker, as it is no longer running");
+                }
+
+                return;
+            }
+
+            match operation.recv(&messages) {
+                Ok(message) => self.handle_message(message),
+                Err(_) => {
+                    //No one can send us messages anymore, so there's nothing else to wait for
+                    if worker.send(work).is_err() {
+                        error!("Failed to deliver work to event group worker, as it is no longer running");
+                    }
+
+                    return;
+                }
+            }
+        }
+    }
 
     fn add_connection(&mut self, channel: Arc<Channel>) {
-        self.currently_connected.insert(channel.id(), channel.clone());
+        let mut registration = channel.network().registration().lock_safe();
+
+        let interest = interest(&channel);
+
+        if let Err(err) = self.poller.add_with_mode(channel.network().raw_fd(), interest, self.poll_mode.into()) {
+            //We would never hear from this channel, so there's no point in keeping it
+            error!("Failed to register channel {} in the poller because {:?}", channel.id(), err);
+
+            drop(registration);
+
+            self.handler.handle_connection_removed(channel.clone(), Some(RusttyError::Poller(err)));
 
-        self.poller.modify(channel.network().raw_fd(), Event::readable(channel.id())).unwrap();
+            channel.notify_closed();
+
+            return;
+        }
+
+        registration.interest = (interest.readable, interest.writable);
+
+        drop(registration);
+
+        self.currently_connected.insert(channel.id(), channel);
     }
 
-    fn remove_connection(&mut self, channel_id: usize) {
+    fn remove_connection(&mut self, channel_id: usize, err: Option<RusttyError>) {
         if let Some(channel) = self.currently_connected.remove(&channel_id) {
             //Delete the channel from our pool
-            self.poller.delete(channel.network().raw_fd()).unwrap();
+            if let Err(err) = self.poller.delete(channel.network().raw_fd()) {
+                //The socket is closed once the channel is dropped, which removes it from the poller anyway
+                debug!("Failed to remove channel {} from the poller because {:?}", channel_id, err);
+            }
+
+            self.throttled_reads.remove(&channel_id);
+            self.throttled_writes.remove(&channel_id);
+            self.backed_off.remove(&channel_id);
+
+            if let Some(err) = &err {
+                debug!("Removing channel {} because of {}", channel_id, err);
+            }
+
+            self.handler.handle_connection_removed(channel.clone(), err);
+
+            channel.notify_closed();
+        }
+    }
+
+    /// Resume the reads and writes of the channels whose throttling or overload backoff has expired
+    fn resume_throttled(&mut self, now: Instant) {
+        let mut resumed: Vec<usize> = Vec::new();
+
+        self.backed_off.retain(|channel_id, until| {
+            let expired = *until <= now;
+
+            if expired {
+                resumed.push(*channel_id);
+            }
+
+            !expired
+        });
+
+        for (throttled, direction) in [(&mut self.throttled_reads, Direction::Read), (&mut self.throttled_writes, Direction::Write)] {
+            throttled.retain(|channel_id, until| {
+                let expired = *until <= now;
+
+                if expired {
+                    if let Some(channel) = self.currently_connected.get(channel_id) {
+                        channel.traffic().resume(direction);
+                    }
+
+                    resumed.push(*channel_id);
+                }
+
+                !expired
+            });
+        }
+
+        resumed.sort_unstable();
+        resumed.dedup();
+
+        for channel_id in resumed {
+            if let Some(channel) = self.currently_connected.get(&channel_id) {
+                if let Err(err) = update_interest(&self.poller, self.poll_mode, channel, true) {
+                    error!("Failed to resume channel {} because {:?}", channel_id, err);
+                }
+            }
         }
     }
 }
 
-impl EventGroupWorkers {
-    fn deliver_io_work(&self, events: Vec<Event>) {
+/// The events the channel is interested in, according to its current state.
+/// We are not interested in reading from channels that are throttled or that have
+/// auto read disabled, and only want to know if we can write to channels that have
+/// pending bytes and are not throttled
+fn interest(channel: &Channel) -> Event {
+    Event {
+        key: channel.id(),
+        readable: channel.is_auto_read() && !channel.traffic().is_paused(Direction::Read),
+        writable: !channel.traffic().is_paused(Direction::Write)
+            && channel.network().has_pending_tx().load(Ordering::SeqCst),
+    }
+}
 
-        //TODO: Split up the work according to the amount of threads we have available
-        
+/// Register the interest of the channel in the poller, according to its current state.
+/// Unless forced, the registration is only changed if the interest has changed.
+/// Changing the registration also re-arms it, so we are notified if the channel is already ready.
+/// Channels with an event in flight are left alone, their worker updates them once it's done
+fn update_interest(poller: &Poller, poll_mode: PollMode, channel: &Channel, force: bool) -> Result<()> {
+    let mut registration = channel.network().registration().lock_safe();
+
+    if registration.in_flight {
+        registration.missed = true;
+
+        return Ok(());
+    }
+
+    let interest = interest(channel);
+
+    if !force && registration.interest == (interest.readable, interest.writable) {
+        return Ok(());
+    }
+
+    apply_interest(poller, poll_mode, channel, &mut registration, interest)
+}
+
+/// Register the interest of the channel once its event is no longer in flight.
+/// The registration is forced if anything was missed in the meantime, as we don't know
+/// whether the channel is still ready
+fn finish_event(poller: &Poller, poll_mode: PollMode, channel: &Channel, force: bool) -> Result<()> {
+    let mut registration = channel.network().registration().lock_safe();
+
+    registration.in_flight = false;
+
+    let force = std::mem::take(&mut registration.missed) || force;
+
+    let interest = interest(channel);
+
+    if !force && registration.interest == (interest.readable, interest.writable) {
+        return Ok(());
+    }
+
+    apply_interest(poller, poll_mode, channel, &mut registration, interest)
+}
+
+fn apply_interest(poller: &Poller, poll_mode: PollMode, channel: &Channel, registration: &mut Registration, interest: Event) -> Result<()> {
+    match poller.modify_with_mode(channel.network().raw_fd(), interest, poll_mode.into()) {
+        Ok(()) => {
+            registration.interest = (interest.readable, interest.writable);
+
+            Ok(())
+        }
+        //The channel is not registered yet (or anymore), its interest is
+        //taken into account when it's added
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(RusttyError::Poller(err))
+    }
+}
+
+impl From<PollMode> for polling::PollMode {
+    fn from(mode: PollMode) -> Self {
+        match mode {
+            PollMode::Oneshot => polling::PollMode::Oneshot,
+            PollMode::Level => polling::PollMode::Level,
+            PollMode::Edge => polling::PollMode::Edge,
+        }
+    }
+}
+
+impl EventGroupWorkers {
+    fn worker_for(&self, channel_id: usize) -> &Sender<IOWork> {
+        &self.workers[channel_id % self.workers.len()]
     }
 }
 
 impl EventGroupHandle {
 
-    pub(crate) fn register_new_connection(&self, channel: Arc<Channel>) {
-        self.tx.send(EventGroupMessage::AddConnection(channel)).unwrap();
+    /// Hand the channel over to the event group, after letting the handler set it up.
+    /// If the event group is no longer running, or its queue is full and the overload
+    /// policy doesn't allow us to wait, the channel is closed right away
+    pub(crate) fn register_new_connection(&self, channel: Channel) -> Result<Arc<Channel>> {
+        let channel = Arc::new(self.handler.handle_connection_established(channel));
+
+        let message = EventGroupMessage::AddConnection(channel.clone());
+
+        let result = if self.overload_policy == OverloadPolicy::Block || self.is_event_loop_thread() {
+            self.send(message)
+        } else {
+            match self.tx.try_send(message) {
+                Ok(()) => {
+                    self.wake_up();
+
+                    Ok(())
+                }
+                Err(TrySendError::Full(_)) => {
+                    self.overload.connection_rejected();
+
+                    Err(RusttyError::Overloaded)
+                }
+                Err(TrySendError::Disconnected(_)) => Err(RusttyError::Registration),
+            }
+        };
+
+        if let Err(err) = result {
+            let overloaded = matches!(err, RusttyError::Overloaded);
+
+            //The channel will never be polled, so it's removed right away
+            self.handler.handle_connection_removed(channel.clone(), Some(err));
+
+            channel.notify_closed();
+
+            return Err(if overloaded { RusttyError::Overloaded } else { RusttyError::Registration });
+        }
+
+        Ok(channel)
+    }
+
+    fn send(&self, message: EventGroupMessage) -> Result<()> {
+        if self.is_event_loop_thread() {
+            //Waiting for room in our own queue would never end
+            DEFERRED_MESSAGES.with(|deferred| deferred.borrow_mut().push_back(message));
+
+            return Ok(());
+        }
+
+        //Never waits for long, as the event loop keeps handling its messages even while
+        //it waits for the workers
+        self.tx.send(message).map_err(|SendError(_)| RusttyError::Registration)?;
+
+        self.wake_up();
+
+        Ok(())
+    }
+
+    /// Wake up the event loop, so it doesn't wait for the poll timeout to handle our message.
+    /// The message has been delivered either way, so failing to do so only delays it
+    fn wake_up(&self) {
+        if let Err(err) = self.poller.notify() {
+            warn!("Failed to wake up the event loop because {:?}", err);
+        }
+    }
+
+    fn is_event_loop_thread(&self) -> bool {
+        self.event_loop_thread.get() == Some(&std::thread::current().id())
+    }
+
+    /// What the overload policy has had to do so far
+    pub(crate) fn overload_stats(&self) -> OverloadStats {
+        self.overload.stats()
+    }
+
+    pub(crate) fn handler(&self) -> &Arc<dyn ChannelHandler> {
+        &self.handler
+    }
+
+    pub(crate) fn traffic(&self) -> &Arc<GroupTraffic> {
+        &self.traffic
+    }
+
+    /// The buffers shared by the workers and channels of this event group
+    pub(crate) fn buffer_pool(&self) -> &Arc<BufferPool> {
+        &self.buffer_pool
+    }
+
+    pub(crate) fn read_sizing(&self) -> ReadSizing {
+        self.read_sizing
+    }
+
+    pub(crate) fn event_budget(&self) -> &EventBudget {
+        &self.event_budget
+    }
+
+    /// Stop reading from the channel for the given amount of time
+    pub(crate) fn throttle_reads(&self, channel: &Channel, delay: Duration) -> Result<()> {
+        channel.traffic().pause(Direction::Read);
+
+        self.send(EventGroupMessage::ThrottleReads(channel.id(), Instant::now() + delay))
+    }
+
+    /// Stop writing the pending bytes of the channel for the given amount of time
+    pub(crate) fn throttle_writes(&self, channel: &Channel, delay: Duration) -> Result<()> {
+        channel.traffic().pause(Direction::Write);
+
+        self.send(EventGroupMessage::ThrottleWrites(channel.id(), Instant::now() + delay))
+    }
+
+    /// Update the events the channel is interested in, after its state has changed
+    pub(crate) fn rearm(&self, channel: &Channel) -> Result<()> {
+        update_interest(&self.poller, self.poll_mode, channel, true)
+    }
+
+    /// Re-arm the channel after one of its events has been handled, which allows the event loop
+    /// to hand it to us again. Oneshot registrations need it every time, as do channels we stopped
+    /// handling early, the others only when the interest changed
+    pub(crate) fn finish_event(&self, channel: &Channel, pending: bool) -> Result<()> {
+        finish_event(&self.poller, self.poll_mode, channel, pending || self.poll_mode == PollMode::Oneshot)
+    }
+
+    /// Let the event group know the channel has bytes waiting for the socket to become writable
+    pub(crate) fn register_write_intention(&self, channel: &Channel) -> Result<()> {
+        self.rearm(channel)
+    }
+
+    /// Remove the channel from the event group, along with the error that caused it (if any)
+    pub(crate) fn close_connection(&self, channel: &Arc<Channel>, err: Option<RusttyError>) -> Result<()> {
+        self.send(EventGroupMessage::RemoveConnection(channel.id(), err))
+    }
+
+}
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use crate::channel::ChannelNetwork;
+    use crate::error::RusttyError;
+    use crate::future::CloseFuture;
+    use super::*;
+
+    struct EchoHandler;
+
+    impl ChannelHandler for EchoHandler {
+        fn handle_connection_established(&self, channel: Channel) -> Channel {
+            channel
+        }
+
+        fn handle_message_received(&self, channel: Arc<Channel>, buf: Vec<u8>) {
+            (&*channel).write_all(&buf).unwrap();
+        }
+
+        fn handle_connection_removed(&self, _channel: Arc<Channel>, _err: Option<RusttyError>) {}
     }
 
-    pub(crate) fn register_write_intention(&self, channel: &Channel) {
+    fn echo_through_event_group(poll_mode: PollMode) {
+        let mut config = BaseConfig::new(2, 1024);
 
+        config.set_poll_mode(poll_mode);
+
+        //Small enough for each round to take several buffers and several events
+        let mut event_budget = EventBudget::default();
+        event_budget.set_read_bytes(1024);
+        event_budget.set_write_bytes(1024);
+        event_budget.set_write_iterations(1);
+
+        config.set_read_sizing(ReadSizing::new(64, 128, 512));
+        config.set_event_budget(event_budget);
+
+        let handle = EventGroup::initialize_event_group(0, &config, Arc::new(EchoHandler)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let (conn, addr) = listener.accept().unwrap();
+        conn.set_nonblocking(true).unwrap();
+
+        let channel = Channel::new(1, ChannelNetwork::new(addr, Box::new(conn), 1024), handle.clone());
+        let close_future = channel.close_future();
+
+        handle.register_new_connection(channel).unwrap();
+
+        //More than one event's worth, so the channel has to be re-armed in between
+        for round in 0..3u8 {
+            let sent = vec![round; 4096];
+
+            client.write_all(&sent).unwrap();
+
+            let mut received = vec![0; sent.len()];
+            client.read_exact(&mut received).unwrap();
+
+            assert_eq!(received, sent);
+        }
+
+        drop(client);
+
+        //The peer closing the connection removes the channel
+        crate::future::block_on(close_future);
+    }
+
+    //Holds the worker on its first message until the gate is opened
+    struct StallingHandler {
+        entered: Sender<usize>,
+        gate: Receiver<()>,
+    }
+
+    impl ChannelHandler for StallingHandler {
+        fn handle_connection_established(&self, channel: Channel) -> Channel {
+            channel
+        }
+
+        fn handle_message_received(&self, channel: Arc<Channel>, _buf: Vec<u8>) {
+            let _ = self.entered.send(channel.id());
+            let _ = self.gate.recv();
+        }
+
+        fn handle_connection_removed(&self, _channel: Arc<Channel>, _err: Option<RusttyError>) {}
+    }
+
+    //Connect channels that each send a message, once the worker is stalled on the first one
+    fn stalled_channels(policy: OverloadPolicy) -> (EventGroupHandle, Receiver<usize>, Sender<()>, Vec<TcpStream>, Vec<CloseFuture>) {
+        let mut config = BaseConfig::new(1, 1024);
+
+        //Level triggered, so the stalled channels keep being reported until they are read
+        config.set_poll_mode(PollMode::Level);
+        config.set_work_queue_capacity(1);
+        config.set_overload_policy(policy);
+
+        let (entered_tx, entered) = crossbeam_channel::unbounded();
+        let (gate_tx, gate) = crossbeam_channel::unbounded();
+
+        let handle = EventGroup::initialize_event_group(0, &config, Arc::new(StallingHandler { entered: entered_tx, gate })).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut clients = Vec::new();
+        let mut close_futures = Vec::new();
+
+        for id in 1..=3 {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+            let (conn, addr) = listener.accept().unwrap();
+            conn.set_nonblocking(true).unwrap();
+
+            let channel = Channel::new(id, ChannelNetwork::new(addr, Box::new(conn), 1024), handle.clone());
+            close_futures.push(channel.close_future());
+
+            handle.register_new_connection(channel).unwrap();
+
+            client.write_all(b"ping").unwrap();
+
+            clients.push(client);
+
+            if id == 1 {
+                //The worker is stalled on the first channel, the next one fills its queue
+                assert_eq!(entered.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+            }
+        }
+
+        (handle, entered, gate_tx, clients, close_futures)
+    }
+
+    fn wait_for_overload(handle: &EventGroupHandle, overloaded: impl Fn(OverloadStats) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while !overloaded(handle.overload_stats()) {
+            assert!(Instant::now() < deadline, "The overload policy was never applied");
+
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn overloaded_workers_shed_channels() {
+        let (handle, _entered, gate_tx, clients, close_futures) = stalled_channels(OverloadPolicy::Shed);
+
+        wait_for_overload(&handle, |stats| stats.shed_connections > 0);
+
+        //The channels whose events are already queued or being handled are not reported again
+        std::thread::sleep(Duration::from_millis(50));
+
+        assert_eq!(handle.overload_stats().shed_connections, 1);
+
+        drop(gate_tx);
+
+        //The shed channel (and then the others, once their peers are gone) are closed
+        drop(clients);
+
+        for close_future in close_futures {
+            crate::future::block_on(close_future);
+        }
+    }
+
+    #[test]
+    fn overloaded_workers_drop_events_and_back_off() {
+        let (handle, entered, gate_tx, clients, close_futures) = stalled_channels(OverloadPolicy::DropNewest);
+
+        wait_for_overload(&handle, |stats| stats.dropped_events > 0);
+
+        std::thread::sleep(Duration::from_millis(50));
+
+        //We back off instead of dropping the event over and over
+        assert!(handle.overload_stats().dropped_events < 20, "{:?}", handle.overload_stats());
+
+        drop(gate_tx);
+
+        //The dropped events are reported again, so every channel is eventually read
+        let mut handled: Vec<usize> = (0..2).map(|_| entered.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        handled.sort_unstable();
+
+        assert_eq!(handled, vec![2, 3]);
+
+        drop(clients);
+
+        for close_future in close_futures {
+            crate::future::block_on(close_future);
+        }
+    }
+
+    //Gives every buffer it receives back to the pool
+    struct ReleasingHandler {
+        received: Sender<Vec<u8>>,
+    }
+
+    impl ChannelHandler for ReleasingHandler {
+        fn handle_connection_established(&self, channel: Channel) -> Channel {
+            channel
+        }
+
+        fn handle_message_received(&self, channel: Arc<Channel>, buf: Vec<u8>) {
+            let _ = self.received.send(buf.clone());
+
+            channel.release_buffer(buf);
+        }
+
+        fn handle_connection_removed(&self, _channel: Arc<Channel>, _err: Option<RusttyError>) {}
+    }
+
+    #[test]
+    fn released_read_buffers_are_reused() {
+        let mut config = BaseConfig::new(1, 1024);
+
+        //Every read takes a buffer of the same class
+        config.set_read_sizing(ReadSizing::new(1024, 1024, 1024));
+
+        let (received_tx, received) = crossbeam_channel::unbounded();
+
+        let handle = EventGroup::initialize_event_group(0, &config, Arc::new(ReleasingHandler { received: received_tx })).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let (conn, addr) = listener.accept().unwrap();
+        conn.set_nonblocking(true).unwrap();
+
+        handle.register_new_connection(Channel::new(1, ChannelNetwork::new(addr, Box::new(conn), 1024), handle.clone())).unwrap();
+
+        //Each message is read in its own readable event
+        for round in 0..3u8 {
+            client.write_all(&[round; 16]).unwrap();
+
+            assert_eq!(received.recv_timeout(Duration::from_secs(5)).unwrap(), vec![round; 16]);
+        }
+
+        //Only the first read had to allocate its buffer
+        let stats = handle.buffer_pool().stats();
+
+        assert!(stats.hits >= 2, "Read buffers were not reused: {:?}", stats);
+    }
+
+    //Counts the channels it sees, holding the event loop on the removal of the first one
+    struct CountingHandler {
+        established: AtomicUsize,
+        removed: Sender<usize>,
+        entered: Sender<()>,
+        gate: Receiver<()>,
+    }
+
+    impl ChannelHandler for CountingHandler {
+        fn handle_connection_established(&self, channel: Channel) -> Channel {
+            self.established.fetch_add(1, Ordering::SeqCst);
+
+            channel
+        }
+
+        fn handle_message_received(&self, _channel: Arc<Channel>, _buf: Vec<u8>) {}
+
+        fn handle_connection_removed(&self, channel: Arc<Channel>, _err: Option<RusttyError>) {
+            if channel.id() == 1 {
+                let _ = self.entered.send(());
+                let _ = self.gate.recv();
+            }
+
+            let _ = self.removed.send(channel.id());
+        }
+    }
+
+    #[test]
+    fn rejected_connections_are_removed() {
+        let mut config = BaseConfig::new(1, 1024);
+
+        config.set_control_queue_capacity(1);
+        config.set_overload_policy(OverloadPolicy::DropNewest);
+
+        let (removed_tx, removed) = crossbeam_channel::unbounded();
+        let (entered_tx, entered) = crossbeam_channel::unbounded();
+        let (gate_tx, gate) = crossbeam_channel::unbounded();
+
+        let handler = Arc::new(CountingHandler { established: AtomicUsize::new(0), removed: removed_tx, entered: entered_tx, gate });
+
+        let handle = EventGroup::initialize_event_group(0, &config, handler.clone()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut clients = Vec::new();
+        let mut results = Vec::new();
+
+        for id in 1..=3 {
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+            let (conn, addr) = listener.accept().unwrap();
+            conn.set_nonblocking(true).unwrap();
+
+            results.push(handle.register_new_connection(Channel::new(id, ChannelNetwork::new(addr, Box::new(conn), 1024), handle.clone())));
+
+            if id == 1 {
+                //The event loop is held on the removal of the first channel, so the next one fills its queue
+                drop(client);
+
+                entered.recv_timeout(Duration::from_secs(5)).unwrap();
+            } else {
+                clients.push(client);
+            }
+        }
+
+        assert!(matches!(results[2], Err(RusttyError::Overloaded)));
+        assert_eq!(removed.recv_timeout(Duration::from_secs(5)).unwrap(), 3);
+
+        gate_tx.send(()).unwrap();
+
+        let close_future = results[1].as_ref().unwrap().close_future();
+
+        drop(clients);
+
+        crate::future::block_on(close_future);
+
+        let mut removed: Vec<usize> = removed.try_iter().collect();
+        removed.sort();
+
+        assert_eq!(removed, vec![1, 2]);
+        assert_eq!(handler.established.load(Ordering::SeqCst), 3);
     }
 
-    pub(crate) fn close_connection(&self, channel: &Arc<Channel>, err: Option<impl Error>) {
+    //Records the bytes each channel receives, holding the worker on the first read until the gate is opened
+    struct RecordingHandler {
+        received: Sender<(usize, usize)>,
+        stalled: AtomicBool,
+        entered: Sender<()>,
+        gate: Receiver<()>,
+    }
+
+    impl ChannelHandler for RecordingHandler {
+        fn handle_connection_established(&self, channel: Channel) -> Channel {
+            channel
+        }
 
-        self.tx.send(EventGroupMessage::RemoveConnection(channel.id())).unwrap();
+        fn handle_message_received(&self, channel: Arc<Channel>, buf: Vec<u8>) {
+            if !self.stalled.swap(true, Ordering::SeqCst) {
+                let _ = self.entered.send(());
+                let _ = self.gate.recv();
+            }
 
-        //TODO: Call handler method to notify of disconnection
+            let _ = self.received.send((channel.id(), buf.len()));
+        }
 
+        fn handle_connection_removed(&self, _channel: Arc<Channel>, _err: Option<RusttyError>) {}
     }
 
-}
\ No newline at end of file
+    fn busy_channels_share_the_worker(poll_mode: PollMode) {
+        const BUSY_BYTES: usize = 64 * 1024;
+
+        let mut config = BaseConfig::new(1, 1024);
+
+        config.set_poll_mode(poll_mode);
+
+        let mut event_budget = EventBudget::default();
+        event_budget.set_read_bytes(256);
+
+        config.set_read_sizing(ReadSizing::new(64, 128, 256));
+        config.set_event_budget(event_budget);
+
+        let (received_tx, received) = crossbeam_channel::unbounded();
+        let (entered_tx, entered) = crossbeam_channel::unbounded();
+        let (gate_tx, gate) = crossbeam_channel::unbounded();
+
+        let handler = RecordingHandler { received: received_tx, stalled: AtomicBool::new(false), entered: entered_tx, gate };
+
+        let handle = EventGroup::initialize_event_group(0, &config, Arc::new(handler)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let mut clients = Vec::new();
+
+        for id in 1..=2 {
+            clients.push(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+
+            let (conn, addr) = listener.accept().unwrap();
+            conn.set_nonblocking(true).unwrap();
+
+            handle.register_new_connection(Channel::new(id, ChannelNetwork::new(addr, Box::new(conn), 1024), handle.clone())).unwrap();
+        }
+
+        //Far more than a single budget, so the busy channel has to be queued again many times
+        clients[0].write_all(&vec![1; BUSY_BYTES]).unwrap();
+
+        entered.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        clients[1].write_all(b"ping").unwrap();
+
+        gate_tx.send(()).unwrap();
+
+        let mut busy_received = 0;
+        let mut busy_received_before_other = None;
+
+        while busy_received < BUSY_BYTES || busy_received_before_other.is_none() {
+            match received.recv_timeout(Duration::from_secs(5)).unwrap() {
+                (1, len) => {
+                    assert!(len <= 256, "Read {} bytes in a single event", len);
+
+                    busy_received += len;
+                }
+                (_, len) => {
+                    assert_eq!(len, 4);
+
+                    busy_received_before_other = Some(busy_received);
+                }
+            }
+        }
+
+        assert_eq!(busy_received, BUSY_BYTES);
+
+        //The other channel didn't have to wait for the busy one to be drained
+        assert!(busy_received_before_other.unwrap() < BUSY_BYTES / 2);
+    }
+
+    #[test]
+    fn event_budgets_are_shared_between_channels() {
+        busy_channels_share_the_worker(PollMode::Edge);
+        busy_channels_share_the_worker(PollMode::Oneshot);
+    }
+
+    //Forwards what it receives, holding the worker on the messages that ask for it until the gate is opened
+    struct GatedHandler {
+        received: Sender<Vec<u8>>,
+        gate: Receiver<()>,
+    }
+
+    impl ChannelHandler for GatedHandler {
+        fn handle_connection_established(&self, channel: Channel) -> Channel {
+            channel
+        }
+
+        fn handle_message_received(&self, _channel: Arc<Channel>, buf: Vec<u8>) {
+            let stall = buf == b"stall";
+
+            let _ = self.received.send(buf);
+
+            if stall {
+                let _ = self.gate.recv();
+            }
+        }
+
+        fn handle_connection_removed(&self, _channel: Arc<Channel>, _err: Option<RusttyError>) {}
+    }
+
+    fn auto_read_holds_messages(poll_mode: PollMode) {
+        const QUIET: Duration = Duration::from_millis(100);
+        const TIMEOUT: Duration = Duration::from_secs(5);
+
+        let mut config = BaseConfig::new(1, 1024);
+
+        config.set_poll_mode(poll_mode);
+
+        let (received_tx, received) = crossbeam_channel::unbounded();
+        let (gate_tx, gate) = crossbeam_channel::unbounded();
+
+        let handle = EventGroup::initialize_event_group(0, &config, Arc::new(GatedHandler { received: received_tx, gate })).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let (conn, addr) = listener.accept().unwrap();
+        conn.set_nonblocking(true).unwrap();
+
+        let channel = handle.register_new_connection(Channel::new(1, ChannelNetwork::new(addr, Box::new(conn), 1024), handle.clone())).unwrap();
+
+        channel.set_auto_read(false).unwrap();
+
+        client.write_all(b"one").unwrap();
+
+        assert!(received.recv_timeout(QUIET).is_err());
+
+        channel.set_auto_read(true).unwrap();
+
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"one");
+
+        //Disabled while an event is in flight, so the worker applies it once it's done
+        client.write_all(b"stall").unwrap();
+
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"stall");
+
+        channel.set_auto_read(false).unwrap();
+
+        gate_tx.send(()).unwrap();
+
+        client.write_all(b"two").unwrap();
+
+        assert!(received.recv_timeout(QUIET).is_err());
+
+        channel.set_auto_read(true).unwrap();
+
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"two");
+
+        //Toggled back and forth while an event is in flight, with bytes arriving in between
+        client.write_all(b"stall").unwrap();
+
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"stall");
+
+        channel.set_auto_read(false).unwrap();
+
+        client.write_all(b"three").unwrap();
+
+        std::thread::sleep(QUIET);
+
+        channel.set_auto_read(true).unwrap();
+
+        gate_tx.send(()).unwrap();
+
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), b"three");
+    }
+
+    #[test]
+    fn auto_read_can_be_toggled() {
+        auto_read_holds_messages(PollMode::Oneshot);
+        auto_read_holds_messages(PollMode::Level);
+        auto_read_holds_messages(PollMode::Edge);
+    }
+
+    #[test]
+    fn registered_channels_receive_events() {
+        echo_through_event_group(PollMode::Oneshot);
+        echo_through_event_group(PollMode::Level);
+        echo_through_event_group(PollMode::Edge);
+    }
+}
@@ -0,0 +1,42 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// What the overload policy of an event group has had to do so far
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OverloadStats {
+    /// The events that were dropped because the queue of their worker was full
+    pub dropped_events: u64,
+    /// The channels that were closed because the queue of their worker was full
+    pub shed_connections: u64,
+    /// The new connections that were closed because the event loop's queue was full
+    pub rejected_connections: u64,
+}
+
+/// The counters behind [OverloadStats], shared by the event loop and the handles
+#[derive(Default)]
+pub(crate) struct OverloadCounters {
+    dropped_events: AtomicU64,
+    shed_connections: AtomicU64,
+    rejected_connections: AtomicU64,
+}
+
+impl OverloadCounters {
+    pub(crate) fn event_dropped(&self) {
+        self.dropped_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_shed(&self) {
+        self.shed_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_rejected(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> OverloadStats {
+        OverloadStats {
+            dropped_events: self.dropped_events.load(Ordering::Relaxed),
+            shed_connections: self.shed_connections.load(Ordering::Relaxed),
+            rejected_connections: self.rejected_connections.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::config::ReadSizing;
+
+/// The size of the buffer we read a channel into, adapted to how much it usually sends.
+/// Doubles whenever a readable event fills it and halves once two events in a row
+/// would also have fit in half of it, staying within the configured bounds
+pub(crate) struct AdaptiveReadSize {
+    sizing: ReadSizing,
+    next: AtomicUsize,
+    //Whether the previous event was small enough to shrink the buffer
+    shrink_pending: AtomicBool,
+}
+
+impl AdaptiveReadSize {
+    pub(crate) fn new(sizing: ReadSizing) -> Self {
+        AdaptiveReadSize {
+            sizing,
+            next: AtomicUsize::new(sizing.initial()),
+            shrink_pending: AtomicBool::new(false),
+        }
+    }
+
+    pub(crate) fn sizing(&self) -> &ReadSizing {
+        &self.sizing
+    }
+
+    /// The size of the buffer to read the next event into
+    pub(crate) fn next_size(&self) -> usize {
+        self.next.load(Ordering::Relaxed)
+    }
+
+    /// Register how many bytes were read for an event.
+    /// The events of a channel are handled by a single worker, so there are no concurrent updates
+    pub(crate) fn record(&self, read: usize) {
+        let current = self.next_size();
+
+        if read >= current {
+            self.next.store((current * 2).min(self.sizing.maximum()), Ordering::Relaxed);
+
+            self.shrink_pending.store(false, Ordering::Relaxed);
+        } else if read <= current / 2 {
+            //A single small read is not enough, as it might just be the end of a larger message
+            if self.shrink_pending.swap(true, Ordering::Relaxed) {
+                self.next.store((current / 2).max(self.sizing.minimum()), Ordering::Relaxed);
+
+                self.shrink_pending.store(false, Ordering::Relaxed);
+            }
+        } else {
+            self.shrink_pending.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_on_full_reads_and_shrinks_on_small_ones() {
+        let size = AdaptiveReadSize::new(ReadSizing::new(256, 1024, 4096));
+
+        size.record(1024);
+        assert_eq!(size.next_size(), 2048);
+
+        size.record(5000);
+        size.record(5000);
+        assert_eq!(size.next_size(), 4096);
+
+        //Only shrinks after two small reads in a row
+        size.record(100);
+        assert_eq!(size.next_size(), 4096);
+        size.record(3000);
+        size.record(100);
+        assert_eq!(size.next_size(), 4096);
+        size.record(100);
+        assert_eq!(size.next_size(), 2048);
+
+        for _ in 0..10 {
+            size.record(0);
+        }
+
+        assert_eq!(size.next_size(), 256);
+    }
+
+    #[test]
+    fn sizing_is_clamped() {
+        assert_eq!(ReadSizing::new(0, 0, 0), ReadSizing::new(1, 1, 1));
+        assert_eq!(ReadSizing::new(512, 64, 128), ReadSizing::new(512, 512, 512));
+        assert_eq!(ReadSizing::new(64, 4096, 1024).initial(), 1024);
+
+        let size = AdaptiveReadSize::new(ReadSizing::new(0, 0, 0));
+
+        size.record(1);
+        assert_eq!(size.next_size(), 1);
+    }
+}
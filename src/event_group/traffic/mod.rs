@@ -0,0 +1,212 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use crate::config::{RateLimit, TrafficShaping};
+use crate::util::LockExt;
+
+/// The direction of the traffic of a channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Read,
+    Write,
+}
+
+/// A token bucket, where each token allows a single byte to go through
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    //Can go negative when more bytes than were available had to be let through
+    //(for example, when a write could not be split), which is then paid back by the refills
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// The token buckets shared by all of the channels of an event group
+pub(crate) struct GroupTraffic {
+    shaping: TrafficShaping,
+    read: Option<TokenBucket>,
+    write: Option<TokenBucket>,
+}
+
+/// The token buckets of a channel, along with the ones of its event group
+pub(crate) struct ChannelTraffic {
+    read: Option<TokenBucket>,
+    write: Option<TokenBucket>,
+    group: Arc<GroupTraffic>,
+    //Whether we have stopped reading or writing until the buckets refill
+    read_paused: AtomicBool,
+    write_paused: AtomicBool,
+}
+
+impl TokenBucket {
+    /// Create a bucket that starts full
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            limit,
+            state: Mutex::new(BucketState {
+                tokens: limit.burst() as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// The amount of bytes that can go through right now
+    pub(crate) fn available(&self) -> u64 {
+        let mut state = self.state.lock_safe();
+
+        self.refill(&mut state);
+
+        state.tokens.max(0.0) as u64
+    }
+
+    /// Take the tokens for bytes that went through
+    pub(crate) fn consume(&self, bytes: u64) {
+        let mut state = self.state.lock_safe();
+
+        self.refill(&mut state);
+
+        state.tokens -= bytes as f64;
+    }
+
+    /// How long until the given amount of bytes can go through
+    pub(crate) fn delay_for(&self, bytes: u64) -> Duration {
+        let mut state = self.state.lock_safe();
+
+        self.refill(&mut state);
+
+        let missing = bytes.min(self.limit.burst()) as f64 - state.tokens;
+
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.limit.bytes_per_second() as f64)
+        }
+    }
+
+    fn refill(&self, state: &mut BucketState) {
+        let now = Instant::now();
+
+        let elapsed = now.saturating_duration_since(state.last_refill);
+
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * self.limit.bytes_per_second() as f64)
+            .min(self.limit.burst() as f64);
+
+        state.last_refill = now;
+    }
+}
+
+impl GroupTraffic {
+    pub(crate) fn new(shaping: TrafficShaping) -> Self {
+        GroupTraffic {
+            shaping,
+            read: shaping.group_read().map(TokenBucket::new),
+            write: shaping.group_write().map(TokenBucket::new),
+        }
+    }
+
+    pub(crate) fn shaping(&self) -> TrafficShaping {
+        self.shaping
+    }
+}
+
+impl ChannelTraffic {
+    pub(crate) fn new(group: Arc<GroupTraffic>) -> Self {
+        let shaping = group.shaping();
+
+        ChannelTraffic {
+            read: shaping.channel_read().map(TokenBucket::new),
+            write: shaping.channel_write().map(TokenBucket::new),
+            group,
+            read_paused: AtomicBool::new(false),
+            write_paused: AtomicBool::new(false),
+        }
+    }
+
+    fn paused(&self, direction: Direction) -> &AtomicBool {
+        match direction {
+            Direction::Read => &self.read_paused,
+            Direction::Write => &self.write_paused,
+        }
+    }
+
+    /// Stop the traffic in the given direction, until it's resumed
+    pub(crate) fn pause(&self, direction: Direction) {
+        self.paused(direction).store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn resume(&self, direction: Direction) {
+        self.paused(direction).store(false, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_paused(&self, direction: Direction) -> bool {
+        self.paused(direction).load(Ordering::SeqCst)
+    }
+
+    fn buckets(&self, direction: Direction) -> impl Iterator<Item=&TokenBucket> {
+        let (channel, group) = match direction {
+            Direction::Read => (&self.read, &self.group.read),
+            Direction::Write => (&self.write, &self.group.write),
+        };
+
+        channel.iter().chain(group.iter())
+    }
+
+    /// The amount of bytes that can go through right now in the given direction.
+    /// None if that direction is not limited
+    pub(crate) fn allowance(&self, direction: Direction) -> Option<u64> {
+        self.buckets(direction).map(TokenBucket::available).min()
+    }
+
+    /// Register that bytes went through in the given direction
+    pub(crate) fn consume(&self, direction: Direction, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+
+        self.buckets(direction).for_each(|bucket| bucket.consume(bytes as u64));
+    }
+
+    /// How long until at least one byte can go through in the given direction
+    pub(crate) fn delay(&self, direction: Direction) -> Duration {
+        self.buckets(direction).map(|bucket| bucket.delay_for(1)).max().unwrap_or(Duration::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_limits_and_refills() {
+        let bucket = TokenBucket::new(RateLimit::new(1000, 100));
+
+        assert_eq!(bucket.available(), 100);
+        assert_eq!(bucket.delay_for(1), Duration::ZERO);
+
+        bucket.consume(150);
+
+        assert_eq!(bucket.available(), 0);
+        //We are 50 bytes in debt, and need another for the next byte to go through
+        assert!(bucket.delay_for(1) > Duration::from_millis(45));
+
+        std::thread::sleep(Duration::from_millis(100));
+
+        assert!(bucket.available() >= 49);
+        assert!(bucket.available() <= 100);
+    }
+
+    #[test]
+    fn empty_limits_still_make_progress() {
+        let limit = RateLimit::new(0, 0);
+
+        assert_eq!((limit.bytes_per_second(), limit.burst()), (1, 1));
+
+        let bucket = TokenBucket::new(limit);
+
+        assert_eq!(bucket.available(), 1);
+        assert!(bucket.delay_for(1) <= Duration::from_secs(1));
+    }
+}
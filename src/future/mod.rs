@@ -0,0 +1,382 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::Thread;
+use futures_core::Stream;
+use crate::channel::Channel;
+use crate::error::RusttyError;
+use crate::util::{ChannelHandler, LockExt};
+
+/// Resolves once the data given to [Channel::write_async] has been written to the socket
+pub type WriteFuture = OneShotFuture<std::io::Result<()>>;
+
+/// Resolves once the channel has been removed from its event group
+pub type CloseFuture = OneShotFuture<()>;
+
+/// Resolves to the channel once the connection has been established
+pub type ConnectFuture = OneShotFuture<crate::error::Result<Arc<Channel>>>;
+
+struct OneShotState<T> {
+    result: Option<T>,
+    waker: Option<Waker>,
+    //Called with the result instead of storing it, see [OneShotFuture::on_complete]
+    listener: Option<Box<dyn FnOnce(T) + Send>>,
+}
+
+/// The completing side of a [OneShotFuture].
+/// Completing it consumes it, so a future is only ever completed once.
+pub(crate) struct OneShot<T> {
+    state: Arc<Mutex<OneShotState<T>>>,
+}
+
+/// A future that is completed by some other thread (usually an event group worker).
+/// These futures do not depend on any particular runtime, they just wake the task that
+/// polled them, so they can be used with any executor (or with [block_on]).
+pub struct OneShotFuture<T> {
+    state: Arc<Mutex<OneShotState<T>>>,
+}
+
+/// Create a new one shot future and the handle used to complete it
+pub(crate) fn one_shot<T>() -> (OneShot<T>, OneShotFuture<T>) {
+    let state = Arc::new(Mutex::new(OneShotState {
+        result: None,
+        waker: None,
+        listener: None,
+    }));
+
+    (OneShot { state: state.clone() }, OneShotFuture { state })
+}
+
+/// Create a future that is already completed with the given value
+pub(crate) fn ready<T>(value: T) -> OneShotFuture<T> {
+    let (completion, future) = one_shot();
+
+    completion.complete(value);
+
+    future
+}
+
+impl<T> OneShot<T> {
+    pub(crate) fn complete(self, value: T) {
+        let mut state = self.state.lock_safe();
+
+        if let Some(listener) = state.listener.take() {
+            drop(state);
+
+            return listener(value);
+        }
+
+        state.result = Some(value);
+
+        let waker = state.waker.take();
+
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> OneShotFuture<T> {
+    /// Call the listener with the result once this future completes, instead of waiting for it.
+    /// If it has already completed, the listener is called right away, otherwise it's called
+    /// by the thread that completes it (usually an event group worker), so it must not block
+    pub fn on_complete<F>(self, listener: F) where F: FnOnce(T) + Send + 'static {
+        let mut state = self.state.lock_safe();
+
+        match state.result.take() {
+            Some(result) => {
+                drop(state);
+
+                listener(result)
+            }
+            None => state.listener = Some(Box::new(listener))
+        }
+    }
+}
+
+impl<T> Future for OneShotFuture<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self.state.lock_safe();
+
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// How many messages a [StreamHandler] holds before it stops reading from its channels
+const DEFAULT_STREAM_CAPACITY: usize = 1024;
+
+struct StreamState<T> {
+    items: VecDeque<T>,
+    //How many items we hold before the stream counts as full
+    capacity: usize,
+    finished: bool,
+    waker: Option<Waker>,
+    //Called once a full stream has been drained to half of its capacity
+    drained_listeners: Vec<Box<dyn FnOnce() + Send>>,
+}
+
+/// The producing side of an [ItemStream]
+pub(crate) struct StreamSender<T> {
+    state: Arc<Mutex<StreamState<T>>>,
+}
+
+/// A stream of items produced by some other thread.
+/// The stream ends once its sender is dropped and all items have been taken.
+/// Senders are never blocked, they are told when the stream is full so they can stop producing
+pub struct ItemStream<T> {
+    state: Arc<Mutex<StreamState<T>>>,
+}
+
+pub(crate) fn item_stream<T>(capacity: usize) -> (StreamSender<T>, ItemStream<T>) {
+    let state = Arc::new(Mutex::new(StreamState {
+        items: VecDeque::new(),
+        capacity: capacity.max(1),
+        finished: false,
+        waker: None,
+        drained_listeners: Vec::new(),
+    }));
+
+    (StreamSender { state: state.clone() }, ItemStream { state })
+}
+
+impl<T> StreamSender<T> {
+    /// Add an item to the stream. Returns whether the stream is now full
+    pub(crate) fn send(&self, item: T) -> bool {
+        let (waker, full) = {
+            let mut state = self.state.lock_safe();
+
+            state.items.push_back(item);
+
+            (state.waker.take(), state.items.len() >= state.capacity)
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+
+        full
+    }
+
+    /// Call the listener once the stream has been drained to half of its capacity.
+    /// It's called right away if the stream has already been drained
+    pub(crate) fn on_drained<F>(&self, listener: F) where F: FnOnce() + Send + 'static {
+        {
+            let mut state = self.state.lock_safe();
+
+            if state.items.len() > state.capacity / 2 {
+                state.drained_listeners.push(Box::new(listener));
+
+                return;
+            }
+        }
+
+        listener()
+    }
+}
+
+impl<T> Drop for StreamSender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut state = self.state.lock_safe();
+
+            state.finished = true;
+
+            state.waker.take()
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Stream for ItemStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut state = self.state.lock_safe();
+
+        if let Some(item) = state.items.pop_front() {
+            if state.items.len() <= state.capacity / 2 && !state.drained_listeners.is_empty() {
+                let listeners = std::mem::take(&mut state.drained_listeners);
+
+                //The listeners might add items to the stream themselves
+                drop(state);
+
+                listeners.into_iter().for_each(|listener| listener());
+            }
+
+            return Poll::Ready(Some(item));
+        }
+
+        if state.finished {
+            return Poll::Ready(None);
+        }
+
+        state.waker = Some(cx.waker().clone());
+
+        Poll::Pending
+    }
+}
+
+/// Wakes a thread that is blocked in [block_on]
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Block the current thread until the future completes.
+/// This is meant for code that does not use an async runtime.
+pub fn block_on<F>(future: F) -> F::Output where F: Future {
+    let mut future = Box::pin(future);
+
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park()
+        }
+    }
+}
+
+/// A stream of the messages received by the channels of a [StreamHandler]
+pub type MessageStream = ItemStream<(Arc<Channel>, Vec<u8>)>;
+
+/// A [ChannelHandler] that delivers the received messages through a [MessageStream],
+/// instead of through callbacks.
+/// Once the stream holds `capacity` messages, we stop reading from the channels that keep
+/// sending us more, until the stream has been drained to half of its capacity
+pub struct StreamHandler {
+    sender: StreamSender<(Arc<Channel>, Vec<u8>)>,
+}
+
+impl StreamHandler {
+    pub fn new() -> (Self, MessageStream) {
+        Self::with_capacity(DEFAULT_STREAM_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> (Self, MessageStream) {
+        let (sender, stream) = item_stream(capacity);
+
+        (StreamHandler { sender }, stream)
+    }
+}
+
+impl ChannelHandler for StreamHandler {
+    fn handle_connection_established(&self, channel: Channel) -> Channel {
+        channel
+    }
+
+    fn handle_message_received(&self, channel: Arc<Channel>, buf: Vec<u8>) {
+        let full = self.sender.send((channel.clone(), buf));
+
+        //Let TCP flow control slow the peer down until the stream has been drained.
+        //Channels whose reads are already disabled are left alone
+        if full && channel.is_auto_read() && channel.set_auto_read(false).is_ok() {
+            self.sender.on_drained(move || {
+                //If this fails, the event loop has stopped and the channel is gone anyway
+                let _ = channel.set_auto_read(true);
+            });
+        }
+    }
+
+    fn handle_connection_removed(&self, _channel: Arc<Channel>, _err: Option<RusttyError>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use super::*;
+
+    //Takes the next item of the stream, if there is one already
+    fn try_next<T>(stream: &mut ItemStream<T>) -> Poll<Option<T>> {
+        let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+
+        Pin::new(stream).poll_next(&mut Context::from_waker(&waker))
+    }
+
+    #[test]
+    fn block_on_waits_for_other_threads() {
+        let (completion, future) = one_shot();
+
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+
+            completion.complete(42);
+        });
+
+        assert_eq!(block_on(future), 42);
+        assert_eq!(block_on(ready("ready")), "ready");
+    }
+
+    #[test]
+    fn listeners_are_called_on_completion() {
+        let (completion, future) = one_shot();
+        let (result_tx, result) = crossbeam_channel::unbounded();
+
+        let listener_tx = result_tx.clone();
+        future.on_complete(move |value| listener_tx.send(value).unwrap());
+
+        assert!(result.try_recv().is_err());
+
+        completion.complete(1);
+
+        //Already completed futures call the listener right away
+        ready(2).on_complete(move |value| result_tx.send(value).unwrap());
+
+        assert_eq!(result.try_iter().collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn full_streams_notify_once_drained() {
+        let (sender, mut stream) = item_stream(4);
+
+        let drained = Arc::new(AtomicUsize::new(0));
+
+        for item in 0..3 {
+            assert!(!sender.send(item));
+        }
+
+        assert!(sender.send(3));
+
+        let listener_drained = drained.clone();
+        sender.on_drained(move || { listener_drained.fetch_add(1, Ordering::SeqCst); });
+
+        assert_eq!(try_next(&mut stream), Poll::Ready(Some(0)));
+        assert_eq!(drained.load(Ordering::SeqCst), 0);
+
+        assert_eq!(try_next(&mut stream), Poll::Ready(Some(1)));
+        assert_eq!(drained.load(Ordering::SeqCst), 1);
+
+        //Already drained, so it's called right away
+        let listener_drained = drained.clone();
+        sender.on_drained(move || { listener_drained.fetch_add(1, Ordering::SeqCst); });
+        assert_eq!(drained.load(Ordering::SeqCst), 2);
+
+        drop(sender);
+
+        assert_eq!(try_next(&mut stream), Poll::Ready(Some(2)));
+        assert_eq!(try_next(&mut stream), Poll::Ready(Some(3)));
+        assert_eq!(try_next(&mut stream), Poll::Ready(None));
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use crate::channel::Channel;
+use crate::future::OneShotFuture;
+use crate::util::LockExt;
+
+/// A set of channels, on which we can perform bulk operations (such as broadcasting).
+/// Channels are removed from the group automatically once they are closed.
+/// Cloning a group gives another handle to the same set of channels
+#[derive(Clone)]
+pub struct ChannelGroup {
+    members: Arc<Mutex<Members>>,
+}
+
+#[derive(Default)]
+struct Members {
+    channels: BTreeMap<usize, Arc<Channel>>,
+    //The channels we are listening to the closing of. This outlives their membership,
+    //so removing a channel and adding it again doesn't register another listener
+    watched: BTreeSet<usize>,
+}
+
+/// A future that completes once the operation has completed on all of the channels
+/// it was performed on, resolving to the result of each channel (by channel id)
+pub struct GroupFuture<T> {
+    pending: Vec<(usize, OneShotFuture<T>)>,
+    results: Vec<(usize, T)>,
+}
+
+impl ChannelGroup {
+    pub fn new() -> Self {
+        ChannelGroup {
+            members: Arc::new(Mutex::new(Members::default())),
+        }
+    }
+
+    /// Add a channel to this group.
+    /// Returns false if the channel was already a member
+    pub fn add(&self, channel: Arc<Channel>) -> bool {
+        let channel_id = channel.id();
+
+        let already_watched = {
+            let mut members = self.members.lock_safe();
+
+            if members.channels.contains_key(&channel_id) {
+                return false;
+            }
+
+            members.channels.insert(channel_id, channel.clone());
+
+            !members.watched.insert(channel_id)
+        };
+
+        if !already_watched {
+            //Only keep a weak reference, so channels that are never closed don't keep the group alive
+            let members: Weak<Mutex<Members>> = Arc::downgrade(&self.members);
+
+            //Called right away if the channel is already closed, so we can't hold the lock here
+            channel.on_close(move || {
+                if let Some(members) = members.upgrade() {
+                    let mut members = members.lock_safe();
+
+                    members.channels.remove(&channel_id);
+                    members.watched.remove(&channel_id);
+                }
+            });
+        }
+
+        true
+    }
+
+    pub fn remove(&self, channel_id: usize) -> Option<Arc<Channel>> {
+        self.members.lock_safe().channels.remove(&channel_id)
+    }
+
+    pub fn contains(&self, channel_id: usize) -> bool {
+        self.members.lock_safe().channels.contains_key(&channel_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.lock_safe().channels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the channels currently in this group
+    pub fn channels(&self) -> Vec<Arc<Channel>> {
+        self.members.lock_safe().channels.values().cloned().collect()
+    }
+
+    /// Write the buffer to every channel in this group.
+    /// The buffer is copied once and shared by every channel that can't write it right away
+    pub fn broadcast(&self, buf: &[u8]) -> GroupFuture<std::io::Result<()>> {
+        self.broadcast_filtered(buf, |_| true)
+    }
+
+    /// Write the buffer to the channels of this group that match the filter
+    pub fn broadcast_filtered<F>(&self, buf: &[u8], filter: F) -> GroupFuture<std::io::Result<()>>
+        where F: Fn(&Arc<Channel>) -> bool {
+        let buf: Arc<[u8]> = Arc::from(buf);
+
+        //Don't hold the lock while writing, so channels can be closed (and removed) in the meantime
+        let pending = self.channels().iter()
+            .filter(|channel| filter(channel))
+            .map(|channel| (channel.id(), channel.send_async(buf.clone())))
+            .collect();
+
+        GroupFuture::new(pending)
+    }
+
+    /// Close every channel in this group
+    pub fn close_all(&self) -> GroupFuture<()> {
+        let pending = self.channels().iter()
+            .map(|channel| {
+                let close_future = channel.close_future();
+
+                channel.close();
+
+                (channel.id(), close_future)
+            })
+            .collect();
+
+        GroupFuture::new(pending)
+    }
+}
+
+impl Default for ChannelGroup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> GroupFuture<T> {
+    fn new(pending: Vec<(usize, OneShotFuture<T>)>) -> Self {
+        GroupFuture {
+            results: Vec::with_capacity(pending.len()),
+            pending,
+        }
+    }
+}
+
+//We never hand out pinned references to the results, so they don't need to be Unpin
+impl<T> Unpin for GroupFuture<T> {}
+
+impl<T> Future for GroupFuture<T> {
+    type Output = Vec<(usize, T)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        let mut i = 0;
+
+        while i < this.pending.len() {
+            let (channel_id, future) = &mut this.pending[i];
+
+            match Pin::new(future).poll(cx) {
+                Poll::Ready(result) => {
+                    this.results.push((*channel_id, result));
+
+                    this.pending.swap_remove(i);
+                }
+                Poll::Pending => i += 1
+            }
+        }
+
+        if this.pending.is_empty() {
+            Poll::Ready(std::mem::take(&mut this.results))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+    use crate::channel::ChannelNetwork;
+    use crate::config::BaseConfig;
+    use crate::error::RusttyError;
+    use crate::event_group::{EventGroup, EventGroupHandle};
+    use crate::future::block_on;
+    use crate::util::ChannelHandler;
+    use super::*;
+
+    struct IgnoringHandler;
+
+    impl ChannelHandler for IgnoringHandler {
+        fn handle_connection_established(&self, channel: Channel) -> Channel {
+            channel
+        }
+
+        fn handle_message_received(&self, _channel: Arc<Channel>, _buf: Vec<u8>) {}
+
+        fn handle_connection_removed(&self, _channel: Arc<Channel>, _err: Option<RusttyError>) {}
+    }
+
+    //A channel that isn't registered in the event group, along with its peer
+    fn connect(handle: &EventGroupHandle, listener: &TcpListener, id: usize) -> (Arc<Channel>, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let (conn, addr) = listener.accept().unwrap();
+        conn.set_nonblocking(true).unwrap();
+
+        (Arc::new(Channel::new(id, ChannelNetwork::new(addr, Box::new(conn), 1024), handle.clone())), client)
+    }
+
+    fn setup() -> (EventGroupHandle, TcpListener) {
+        let handle = EventGroup::initialize_event_group(0, &BaseConfig::new(1, 1024), Arc::new(IgnoringHandler)).unwrap();
+
+        (handle, TcpListener::bind("127.0.0.1:0").unwrap())
+    }
+
+    #[test]
+    fn channels_are_added_once_and_removed_on_close() {
+        let (handle, listener) = setup();
+
+        let (first, _first_peer) = connect(&handle, &listener, 1);
+        let (second, _second_peer) = connect(&handle, &listener, 2);
+
+        let group = ChannelGroup::new();
+
+        assert!(group.add(first.clone()));
+        assert!(!group.add(first.clone()));
+        assert!(group.add(second.clone()));
+        assert_eq!(group.len(), 2);
+
+        assert_eq!(group.remove(1).map(|channel| channel.id()), Some(1));
+        assert!(group.remove(1).is_none());
+
+        //Adding it back keeps the listener that was registered the first time
+        assert!(group.add(first.clone()));
+
+        first.notify_closed();
+
+        assert!(!group.contains(1));
+        assert!(group.contains(2));
+
+        //Already closed channels are removed right away
+        assert!(group.add(first));
+        assert!(!group.contains(1));
+        assert_eq!(group.channels().iter().map(|channel| channel.id()).collect::<Vec<_>>(), vec![second.id()]);
+    }
+
+    #[test]
+    fn broadcasts_reach_the_filtered_channels() {
+        let (handle, listener) = setup();
+
+        let (first, mut first_peer) = connect(&handle, &listener, 1);
+        let (second, mut second_peer) = connect(&handle, &listener, 2);
+
+        let group = ChannelGroup::new();
+
+        group.add(first);
+        group.add(second);
+
+        let mut results = block_on(group.broadcast_filtered(b"ping", |channel| channel.id() == 2));
+        results.sort_by_key(|(channel_id, _)| *channel_id);
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, 2);
+        assert!(results[0].1.is_ok());
+
+        let mut received = [0; 4];
+        second_peer.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"ping");
+
+        let results = block_on(group.broadcast(b"pong"));
+        assert_eq!(results.len(), 2);
+
+        first_peer.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"pong");
+
+        second_peer.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"pong");
+
+        //The filtered broadcast never reached the first channel
+        first_peer.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        assert!(first_peer.read(&mut received).is_err());
+    }
+}
@@ -1,8 +1,15 @@
-mod server;
-mod config;
+pub mod server;
+pub mod config;
 mod event_group;
-mod channel;
-mod util;
+pub mod channel;
+pub mod util;
+pub mod codec;
+pub mod rpc;
+pub mod future;
+pub mod client;
+pub mod group;
+pub mod pubsub;
+pub mod error;
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
@@ -0,0 +1,462 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, Weak};
+use log::debug;
+use crate::channel::Channel;
+use crate::error::RusttyError;
+use crate::util::{ChannelHandler, LockExt};
+
+/// The separator of the segments of a topic name
+const SEGMENT_SEPARATOR: char = '.';
+/// Matches exactly one segment
+const SINGLE_WILDCARD: &str = "*";
+/// Matches zero or more segments
+const MULTI_WILDCARD: &str = "#";
+
+/// A topic pattern, made of dot separated segments.
+/// A `*` segment matches exactly one segment and a `#` segment matches any amount of segments
+/// (including none), so `prices.*.eur` matches `prices.btc.eur` and `prices.#` matches
+/// `prices`, `prices.btc` and `prices.btc.eur`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopicPattern {
+    segments: Vec<String>,
+}
+
+/// The outcome of publishing a message
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PublishResult {
+    /// The channels the message was written to
+    pub delivered: usize,
+    /// The channels that were skipped because they are too far behind
+    pub skipped: usize,
+    /// The channels we failed to write to
+    pub failed: usize,
+}
+
+/// An in process topic router.
+/// Channels subscribe to topics (or topic patterns) and messages published to a topic are
+/// written to all of the channels subscribed to it.
+///
+/// Channels that have more than the configured amount of bytes waiting to be written
+/// are considered slow, and do not receive messages until they catch up.
+/// The subscriptions of a channel are removed automatically once it is closed
+pub struct TopicRouter {
+    max_pending_bytes: u64,
+    subscriptions: Mutex<Subscriptions>,
+}
+
+#[derive(Default)]
+struct Subscriptions {
+    /// Topics without wildcards, by name
+    exact: BTreeMap<String, BTreeMap<usize, Arc<Channel>>>,
+    /// Patterns with wildcards, by their textual representation
+    patterns: BTreeMap<String, (TopicPattern, BTreeMap<usize, Arc<Channel>>)>,
+    /// The topics each channel is subscribed to, so we can clean them up
+    by_channel: BTreeMap<usize, BTreeSet<String>>,
+    //The channels we are listening to the closing of. This outlives their subscriptions,
+    //so unsubscribing a channel and subscribing it again doesn't register another listener
+    watched: BTreeSet<usize>,
+}
+
+/// A [ChannelHandler] that removes the subscriptions of channels from the router
+/// when they are removed, delegating everything to the inner handler
+pub struct TopicRouterHandler<H> where H: ChannelHandler {
+    router: Arc<TopicRouter>,
+    inner: H,
+}
+
+impl TopicPattern {
+    pub fn parse(pattern: &str) -> Self {
+        TopicPattern {
+            segments: pattern.split(SEGMENT_SEPARATOR).map(str::to_string).collect(),
+        }
+    }
+
+    pub fn has_wildcards(&self) -> bool {
+        self.segments.iter().any(|segment| segment == SINGLE_WILDCARD || segment == MULTI_WILDCARD)
+    }
+
+    pub fn matches(&self, topic: &str) -> bool {
+        let topic: Vec<&str> = topic.split(SEGMENT_SEPARATOR).collect();
+
+        matches_segments(&self.segments, &topic)
+    }
+}
+
+/// Match the topic against the pattern, one pattern segment at a time.
+/// We keep track of every amount of topic segments the pattern could have consumed so far,
+/// so patterns with several `#` segments take linear time per segment instead of backtracking
+fn matches_segments(pattern: &[String], topic: &[&str]) -> bool {
+    //Whether the pattern segments seen so far can match the first `i` topic segments
+    let mut matched = vec![false; topic.len() + 1];
+
+    matched[0] = true;
+
+    for segment in pattern {
+        if segment == MULTI_WILDCARD {
+            //Consume any amount of segments after the shortest match so far
+            match matched.iter().position(|matched| *matched) {
+                Some(shortest) => matched[shortest..].fill(true),
+                None => return false
+            }
+        } else {
+            for i in (1..=topic.len()).rev() {
+                matched[i] = matched[i - 1] && (segment == SINGLE_WILDCARD || segment == topic[i - 1]);
+            }
+
+            matched[0] = false;
+        }
+    }
+
+    matched[topic.len()]
+}
+
+impl TopicRouter {
+    pub fn new(max_pending_bytes: u64) -> Self {
+        TopicRouter {
+            max_pending_bytes,
+            subscriptions: Mutex::new(Subscriptions::default()),
+        }
+    }
+
+    /// Subscribe the channel to a topic, or to all topics that match a pattern
+    pub fn subscribe(self: &Arc<Self>, channel: &Arc<Channel>, topic: &str) {
+        let channel_id = channel.id();
+
+        let already_watched = {
+            let mut subscriptions = self.subscriptions.lock_safe();
+
+            let pattern = TopicPattern::parse(topic);
+
+            let subscribers = if pattern.has_wildcards() {
+                &mut subscriptions.patterns.entry(topic.to_string())
+                    .or_insert_with(|| (pattern, BTreeMap::new())).1
+            } else {
+                subscriptions.exact.entry(topic.to_string()).or_default()
+            };
+
+            subscribers.insert(channel_id, channel.clone());
+
+            subscriptions.by_channel.entry(channel_id).or_default().insert(topic.to_string());
+
+            !subscriptions.watched.insert(channel_id)
+        };
+
+        if !already_watched {
+            //Only keep a weak reference, so channels that are never closed don't keep the router alive
+            let router: Weak<TopicRouter> = Arc::downgrade(self);
+
+            //Called right away if the channel is already closed, so we can't hold the lock here
+            channel.on_close(move || {
+                if let Some(router) = router.upgrade() {
+                    let mut subscriptions = router.subscriptions.lock_safe();
+
+                    subscriptions.remove_channel(channel_id);
+                    subscriptions.watched.remove(&channel_id);
+                }
+            });
+        }
+    }
+
+    /// Unsubscribe the channel from a topic (or pattern) it was subscribed to
+    pub fn unsubscribe(&self, channel_id: usize, topic: &str) {
+        let mut subscriptions = self.subscriptions.lock_safe();
+
+        subscriptions.remove_subscription(channel_id, topic);
+
+        if let Some(topics) = subscriptions.by_channel.get_mut(&channel_id) {
+            topics.remove(topic);
+
+            if topics.is_empty() {
+                subscriptions.by_channel.remove(&channel_id);
+            }
+        }
+    }
+
+    /// Remove all the subscriptions of a channel
+    pub fn unsubscribe_all(&self, channel_id: usize) {
+        self.subscriptions.lock_safe().remove_channel(channel_id);
+    }
+
+    /// The topics (and patterns) the channel is subscribed to
+    pub fn subscriptions_of(&self, channel_id: usize) -> Vec<String> {
+        self.subscriptions.lock_safe().by_channel.get(&channel_id)
+            .map(|topics| topics.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Publish a message to a topic, writing it to all of the channels subscribed to it.
+    /// Channels subscribed through more than one matching pattern only receive it once
+    pub fn publish(&self, topic: &str, buf: &[u8]) -> PublishResult {
+        self.publish_shared(topic, Arc::from(buf))
+    }
+
+    /// Publish a shared message to a topic.
+    /// The message is queued on the channels that can't take it right away without being copied
+    pub fn publish_shared(&self, topic: &str, buf: Arc<[u8]>) -> PublishResult {
+        let subscribers = self.subscribers(topic);
+
+        let mut result = PublishResult::default();
+
+        //We don't hold the subscriptions lock while writing
+        for channel in subscribers.values() {
+            if channel.pending_write_bytes() > self.max_pending_bytes {
+                result.skipped += 1;
+
+                continue;
+            }
+
+            match channel.send(buf.clone()) {
+                Ok(_) => result.delivered += 1,
+                Err(err) => {
+                    debug!("Failed to publish message on topic {} to channel {}: {:?}", topic, channel.id(), err);
+
+                    result.failed += 1;
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Get the channels subscribed to a topic
+    pub fn subscribers(&self, topic: &str) -> BTreeMap<usize, Arc<Channel>> {
+        let subscriptions = self.subscriptions.lock_safe();
+
+        let mut subscribers = subscriptions.exact.get(topic).cloned().unwrap_or_default();
+
+        for (pattern, channels) in subscriptions.patterns.values() {
+            if pattern.matches(topic) {
+                subscribers.extend(channels.iter().map(|(id, channel)| (*id, channel.clone())));
+            }
+        }
+
+        subscribers
+    }
+}
+
+impl Subscriptions {
+    fn remove_channel(&mut self, channel_id: usize) {
+        if let Some(topics) = self.by_channel.remove(&channel_id) {
+            for topic in topics {
+                self.remove_subscription(channel_id, &topic);
+            }
+        }
+    }
+
+    fn remove_subscription(&mut self, channel_id: usize, topic: &str) {
+        if let Some(channels) = self.exact.get_mut(topic) {
+            channels.remove(&channel_id);
+
+            if channels.is_empty() {
+                self.exact.remove(topic);
+            }
+        } else if let Some((_, channels)) = self.patterns.get_mut(topic) {
+            channels.remove(&channel_id);
+
+            if channels.is_empty() {
+                self.patterns.remove(topic);
+            }
+        }
+    }
+}
+
+impl<H> TopicRouterHandler<H> where H: ChannelHandler {
+    pub fn new(router: Arc<TopicRouter>, inner: H) -> Self {
+        TopicRouterHandler {
+            router,
+            inner,
+        }
+    }
+
+    pub fn router(&self) -> &Arc<TopicRouter> {
+        &self.router
+    }
+}
+
+impl<H> ChannelHandler for TopicRouterHandler<H> where H: ChannelHandler {
+    fn handle_connection_established(&self, channel: Channel) -> Channel {
+        self.inner.handle_connection_established(channel)
+    }
+
+    fn handle_message_received(&self, channel: Arc<Channel>, buf: Vec<u8>) {
+        self.inner.handle_message_received(channel, buf)
+    }
+
+    fn handle_connection_removed(&self, channel: Arc<Channel>, err: Option<RusttyError>) {
+        self.router.unsubscribe_all(channel.id());
+
+        self.inner.handle_connection_removed(channel, err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use crate::channel::ChannelNetwork;
+    use crate::config::BaseConfig;
+    use crate::event_group::{EventGroup, EventGroupHandle};
+    use super::*;
+
+    struct IgnoringHandler;
+
+    impl ChannelHandler for IgnoringHandler {
+        fn handle_connection_established(&self, channel: Channel) -> Channel {
+            channel
+        }
+
+        fn handle_message_received(&self, _channel: Arc<Channel>, _buf: Vec<u8>) {}
+
+        fn handle_connection_removed(&self, _channel: Arc<Channel>, _err: Option<RusttyError>) {}
+    }
+
+    //A channel that isn't registered in the event group, along with its peer
+    fn connect(handle: &EventGroupHandle, listener: &TcpListener, id: usize) -> (Arc<Channel>, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        let (conn, addr) = listener.accept().unwrap();
+        conn.set_nonblocking(true).unwrap();
+
+        (Arc::new(Channel::new(id, ChannelNetwork::new(addr, Box::new(conn), 1024), handle.clone())), client)
+    }
+
+    fn setup() -> (EventGroupHandle, TcpListener) {
+        let handle = EventGroup::initialize_event_group(0, &BaseConfig::new(1, 1024), Arc::new(IgnoringHandler)).unwrap();
+
+        (handle, TcpListener::bind("127.0.0.1:0").unwrap())
+    }
+
+    #[test]
+    fn messages_are_delivered_once_per_channel() {
+        let (handle, listener) = setup();
+
+        let (first, mut first_peer) = connect(&handle, &listener, 1);
+        let (second, mut second_peer) = connect(&handle, &listener, 2);
+
+        let router = Arc::new(TopicRouter::new(1024));
+
+        router.subscribe(&first, "prices.btc.eur");
+        router.subscribe(&first, "prices.*.eur");
+        router.subscribe(&first, "prices.#");
+        router.subscribe(&second, "prices.*.usd");
+
+        assert_eq!(router.publish("prices.btc.eur", b"eur"), PublishResult { delivered: 1, skipped: 0, failed: 0 });
+        assert_eq!(router.publish("prices.btc.usd", b"usd"), PublishResult { delivered: 2, skipped: 0, failed: 0 });
+
+        //A duplicate would show up before the second message
+        let mut received = [0; 6];
+
+        first_peer.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"eurusd");
+
+        second_peer.read_exact(&mut received[..3]).unwrap();
+        assert_eq!(&received[..3], b"usd");
+    }
+
+    #[test]
+    fn slow_channels_are_skipped() {
+        let (handle, listener) = setup();
+
+        let (slow, _slow_peer) = connect(&handle, &listener, 1);
+        let (fast, _fast_peer) = connect(&handle, &listener, 2);
+
+        //Never registered, so the bytes the peer doesn't read stay queued
+        (&*slow).write_all(&vec![1; 8 * 1024 * 1024]).unwrap();
+
+        assert!(slow.pending_write_bytes() > 1024);
+
+        let router = Arc::new(TopicRouter::new(1024));
+
+        router.subscribe(&slow, "news");
+        router.subscribe(&fast, "news");
+
+        assert_eq!(router.publish("news", b"hello"), PublishResult { delivered: 1, skipped: 1, failed: 0 });
+    }
+
+    #[test]
+    fn channels_can_unsubscribe() {
+        let (handle, listener) = setup();
+
+        let (channel, _peer) = connect(&handle, &listener, 1);
+
+        let router = Arc::new(TopicRouter::new(1024));
+
+        router.subscribe(&channel, "news");
+        router.subscribe(&channel, "sports.*");
+
+        router.unsubscribe(1, "sports.*");
+
+        assert_eq!(router.subscriptions_of(1), vec!["news".to_string()]);
+        assert!(router.subscribers("sports.tennis").is_empty());
+        assert_eq!(router.publish("sports.tennis", b"score"), PublishResult::default());
+
+        router.unsubscribe(1, "news");
+
+        assert!(router.subscriptions_of(1).is_empty());
+        assert!(router.subscribers("news").is_empty());
+    }
+
+    #[test]
+    fn subscriptions_are_removed_on_close() {
+        let (handle, listener) = setup();
+
+        let (first, _first_peer) = connect(&handle, &listener, 1);
+        let (second, _second_peer) = connect(&handle, &listener, 2);
+
+        let router = Arc::new(TopicRouter::new(1024));
+
+        router.subscribe(&first, "news");
+        router.subscribe(&first, "sports.#");
+        router.subscribe(&second, "news");
+
+        first.notify_closed();
+
+        assert!(router.subscriptions_of(1).is_empty());
+        assert!(router.subscribers("sports.tennis").is_empty());
+        assert_eq!(router.subscribers("news").keys().copied().collect::<Vec<_>>(), vec![2]);
+
+        //The router only holds on to the channels it routes to, not the other way around
+        drop(router);
+
+        second.notify_closed();
+    }
+
+    #[test]
+    fn wildcard_patterns() {
+        let single = TopicPattern::parse("prices.*.eur");
+
+        assert!(single.matches("prices.btc.eur"));
+        assert!(!single.matches("prices.btc.usd"));
+        assert!(!single.matches("prices.eur"));
+
+        let multi = TopicPattern::parse("prices.#");
+
+        assert!(multi.matches("prices"));
+        assert!(multi.matches("prices.btc.eur"));
+        assert!(!multi.matches("volumes.btc"));
+
+        let middle = TopicPattern::parse("a.#.z");
+
+        assert!(middle.matches("a.z"));
+        assert!(middle.matches("a.b.c.z"));
+        assert!(!middle.matches("a.b.c"));
+        assert!(!TopicPattern::parse("a.b").has_wildcards());
+    }
+
+    #[test]
+    fn repeated_multi_wildcards() {
+        let pattern = TopicPattern::parse("#.a.#.*.#");
+
+        assert!(pattern.matches("a.b"));
+        assert!(pattern.matches("x.a.y.z.b"));
+        assert!(!pattern.matches("a"));
+        assert!(!pattern.matches("x.y.z"));
+        assert!(TopicPattern::parse("#.#").matches(""));
+
+        //Would take exponential time with backtracking
+        let pattern = TopicPattern::parse(&["#"; 20].join(".b."));
+        let topic = ["a"; 200].join(".");
+
+        assert!(!pattern.matches(&topic));
+    }
+}
@@ -0,0 +1,491 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use log::{debug, warn};
+use crate::channel::Channel;
+use crate::codec::MessageHandler;
+use crate::codec::varint::{DelimitedMessage, VarintDelimitedCodec};
+use crate::future::{one_shot, OneShot, OneShotFuture};
+use crate::util::LockExt;
+
+/// How often we check for requests that have timed out
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The size of the RPC frame header: the kind and the correlation id
+const HEADER_LEN: usize = 9;
+
+/// The result of a request
+pub type RpcResult = Result<Vec<u8>, RpcError>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RpcKind {
+    Request,
+    Response,
+    /// A response that reports that the request failed
+    ErrorResponse,
+}
+
+/// The frame exchanged by RPC endpoints.
+/// On the wire, it's the kind, followed by the correlation id and the payload
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RpcFrame {
+    kind: RpcKind,
+    correlation_id: u64,
+    payload: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum RpcError {
+    /// No response was received before the timeout
+    Timeout,
+    /// The channel was removed before the response arrived
+    ChannelClosed,
+    /// The peer answered with an error response
+    Remote(Vec<u8>),
+    /// We failed to send the request
+    Io(io::Error),
+}
+
+/// Handles the requests received from the peers
+pub trait RequestHandler: Sync + Send {
+    /// Handle a request. The responder can be used to answer it
+    /// (it does not have to be done before this method returns)
+    fn handle_request(&self, responder: Responder, payload: Vec<u8>);
+}
+
+/// Used to answer a request
+pub struct Responder {
+    channel: Arc<Channel>,
+    correlation_id: u64,
+    max_message_size: usize,
+}
+
+/// The request/response endpoint. This is the [MessageHandler] that should be used with a
+/// [crate::codec::CodecHandler] decoding [RpcFrame]s with a [VarintDelimitedCodec].
+/// Requests are sent through the [RpcClient] obtained from [RpcEndpoint::client]
+pub struct RpcEndpoint<H> where H: RequestHandler {
+    handler: H,
+    client: RpcClient,
+}
+
+/// Sends requests and matches the responses to the callers waiting for them
+#[derive(Clone)]
+pub struct RpcClient {
+    shared: Arc<PendingRequests>,
+    default_timeout: Duration,
+    max_message_size: usize,
+}
+
+struct PendingRequests {
+    next_correlation_id: AtomicU64,
+    pending: Mutex<BTreeMap<u64, PendingRequest>>,
+}
+
+struct PendingRequest {
+    channel_id: usize,
+    deadline: Instant,
+    completion: Completion,
+}
+
+/// How the caller is waiting for the response
+enum Completion {
+    Callback(Box<dyn FnOnce(RpcResult) + Send>),
+    Blocking(crossbeam_channel::Sender<RpcResult>),
+    Future(OneShot<RpcResult>),
+}
+
+/// A future that resolves to the response of a request.
+/// It does not depend on any particular runtime
+pub type RpcFuture = OneShotFuture<RpcResult>;
+
+impl RpcFrame {
+    pub fn new(kind: RpcKind, correlation_id: u64, payload: Vec<u8>) -> Self {
+        RpcFrame {
+            kind,
+            correlation_id,
+            payload,
+        }
+    }
+
+    pub fn kind(&self) -> RpcKind {
+        self.kind
+    }
+
+    pub fn correlation_id(&self) -> u64 {
+        self.correlation_id
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+impl DelimitedMessage for RpcFrame {
+    fn decode_message(mut payload: Vec<u8>) -> io::Result<Self> {
+        if payload.len() < HEADER_LEN {
+            return Err(io::Error::new(ErrorKind::InvalidData, "RPC frame is too short"));
+        }
+
+        let kind = match payload[0] {
+            0 => RpcKind::Request,
+            1 => RpcKind::Response,
+            2 => RpcKind::ErrorResponse,
+            _ => return Err(io::Error::new(ErrorKind::InvalidData, "Unknown RPC frame kind"))
+        };
+
+        let mut correlation_id = [0; 8];
+
+        correlation_id.copy_from_slice(&payload[1..HEADER_LEN]);
+
+        payload.drain(..HEADER_LEN);
+
+        Ok(RpcFrame::new(kind, u64::from_be_bytes(correlation_id), payload))
+    }
+
+    fn encode_message(&self, dst: &mut Vec<u8>) -> io::Result<()> {
+        let kind = match self.kind {
+            RpcKind::Request => 0,
+            RpcKind::Response => 1,
+            RpcKind::ErrorResponse => 2,
+        };
+
+        dst.reserve(HEADER_LEN + self.payload.len());
+        dst.push(kind);
+        dst.extend_from_slice(&self.correlation_id.to_be_bytes());
+        dst.extend_from_slice(&self.payload);
+
+        Ok(())
+    }
+}
+
+impl<H> RpcEndpoint<H> where H: RequestHandler {
+    /// Create a new endpoint. Requests that do not get a response within the
+    /// default timeout fail with [RpcError::Timeout].
+    /// Fails if the thread that checks for timeouts can't be started
+    pub fn new(handler: H, default_timeout: Duration, max_message_size: usize) -> crate::error::Result<Self> {
+        let shared = Arc::new(PendingRequests::new());
+
+        PendingRequests::start_timeout_checker(Arc::downgrade(&shared))?;
+
+        Ok(RpcEndpoint {
+            handler,
+            client: RpcClient {
+                shared,
+                default_timeout,
+                max_message_size,
+            },
+        })
+    }
+
+    pub fn client(&self) -> &RpcClient {
+        &self.client
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+}
+
+impl<H> MessageHandler<RpcFrame> for RpcEndpoint<H> where H: RequestHandler {
+    fn handle_message(&self, channel: &Arc<Channel>, message: RpcFrame) {
+        let RpcFrame { kind, correlation_id, payload } = message;
+
+        match kind {
+            RpcKind::Request => {
+                let responder = Responder {
+                    channel: channel.clone(),
+                    correlation_id,
+                    max_message_size: self.client.max_message_size,
+                };
+
+                self.handler.handle_request(responder, payload);
+            }
+            RpcKind::Response => {
+                self.client.shared.complete(channel.id(), correlation_id, Ok(payload));
+            }
+            RpcKind::ErrorResponse => {
+                self.client.shared.complete(channel.id(), correlation_id, Err(RpcError::Remote(payload)));
+            }
+        }
+    }
+
+    fn handle_connection_removed(&self, channel: &Arc<Channel>) {
+        self.client.shared.fail_channel(channel.id());
+    }
+}
+
+impl RpcClient {
+    /// Send a request, calling the callback with its response
+    pub fn call<F>(&self, channel: &Arc<Channel>, payload: Vec<u8>, callback: F)
+        where F: FnOnce(RpcResult) + Send + 'static {
+        self.send_request(channel, payload, self.default_timeout, Completion::Callback(Box::new(callback)));
+    }
+
+    /// Send a request and block the calling thread until its response arrives.
+    /// Must not be called from the thread that delivers the messages of this channel,
+    /// as the response would never be delivered.
+    pub fn call_blocking(&self, channel: &Arc<Channel>, payload: Vec<u8>) -> RpcResult {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+
+        self.send_request(channel, payload, self.default_timeout, Completion::Blocking(tx));
+
+        //The timeout checker always completes the request, this is just a safeguard
+        rx.recv_timeout(self.default_timeout + TIMEOUT_CHECK_INTERVAL * 10)
+            .unwrap_or(Err(RpcError::Timeout))
+    }
+
+    /// Send a request, returning a future that resolves to its response
+    pub fn call_future(&self, channel: &Arc<Channel>, payload: Vec<u8>) -> RpcFuture {
+        let (completion, future) = one_shot();
+
+        self.send_request(channel, payload, self.default_timeout, Completion::Future(completion));
+
+        future
+    }
+
+    /// Send a request with a timeout other than the default one
+    pub fn call_with_timeout<F>(&self, channel: &Arc<Channel>, payload: Vec<u8>, timeout: Duration, callback: F)
+        where F: FnOnce(RpcResult) + Send + 'static {
+        self.send_request(channel, payload, timeout, Completion::Callback(Box::new(callback)));
+    }
+
+    /// The amount of requests still waiting for a response
+    pub fn pending_requests(&self) -> usize {
+        self.shared.pending.lock_safe().len()
+    }
+
+    fn send_request(&self, channel: &Arc<Channel>, payload: Vec<u8>, timeout: Duration, completion: Completion) {
+        //Register the request before sending it, so the response can't arrive before we know about it
+        let correlation_id = self.shared.register(channel.id(), timeout, completion);
+
+        let frame = RpcFrame::new(RpcKind::Request, correlation_id, payload);
+
+        let mut codec = VarintDelimitedCodec::new(self.max_message_size);
+
+        if let Err(err) = channel.write_encoded(&mut codec, frame) {
+            self.shared.complete(channel.id(), correlation_id, Err(RpcError::Io(err)));
+        }
+    }
+}
+
+impl PendingRequests {
+    fn new() -> Self {
+        PendingRequests {
+            next_correlation_id: AtomicU64::new(0),
+            pending: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Keep track of a request sent through the given channel, returning its correlation id
+    fn register(&self, channel_id: usize, timeout: Duration, completion: Completion) -> u64 {
+        let correlation_id = self.next_correlation_id.fetch_add(1, Ordering::Relaxed);
+
+        self.pending.lock_safe().insert(correlation_id, PendingRequest {
+            channel_id,
+            deadline: Instant::now() + timeout,
+            completion,
+        });
+
+        correlation_id
+    }
+
+    /// Complete the request with the response received through the given channel.
+    /// Correlation ids are only unique to us, so a response that arrives through any other
+    /// channel than the one the request was sent through is not meant for it
+    fn complete(&self, channel_id: usize, correlation_id: u64, result: RpcResult) {
+        let mut pending = self.pending.lock_safe();
+
+        let request = match pending.get(&correlation_id) {
+            Some(request) if request.channel_id == channel_id => pending.remove(&correlation_id),
+            Some(request) => {
+                warn!("Ignoring response to request {} received through channel {}, as it was sent through channel {}",
+                    correlation_id, channel_id, request.channel_id);
+
+                None
+            }
+            None => {
+                //The request has probably timed out already
+                debug!("Received a response for unknown request {} through channel {}", correlation_id, channel_id);
+
+                None
+            }
+        };
+
+        //Don't hold the lock while calling back the caller, it may send another request
+        drop(pending);
+
+        if let Some(request) = request {
+            request.completion.complete(result);
+        }
+    }
+
+    /// Fail all of the requests that were sent through the given channel
+    fn fail_channel(&self, channel_id: usize) {
+        let failed = self.remove_where(|request| request.channel_id == channel_id);
+
+        for request in failed {
+            request.completion.complete(Err(RpcError::ChannelClosed));
+        }
+    }
+
+    fn remove_where<F>(&self, predicate: F) -> Vec<PendingRequest> where F: Fn(&PendingRequest) -> bool {
+        let mut pending = self.pending.lock_safe();
+
+        let ids: Vec<u64> = pending.iter()
+            .filter(|(_, request)| predicate(request))
+            .map(|(id, _)| *id)
+            .collect();
+
+        ids.into_iter().filter_map(|id| pending.remove(&id)).collect()
+    }
+
+    /// Periodically fail the requests whose deadline has passed.
+    /// The thread stops once the endpoint (and all of its clients) is dropped
+    fn start_timeout_checker(shared: Weak<PendingRequests>) -> crate::error::Result<()> {
+        std::thread::Builder::new()
+            .name("RPC timeout checker".to_string())
+            .spawn(move || {
+                loop {
+                    std::thread::sleep(TIMEOUT_CHECK_INTERVAL);
+
+                    let shared = match shared.upgrade() {
+                        Some(shared) => shared,
+                        None => break
+                    };
+
+                    let now = Instant::now();
+
+                    for request in shared.remove_where(|request| request.deadline <= now) {
+                        request.completion.complete(Err(RpcError::Timeout));
+                    }
+                }
+            })?;
+
+        Ok(())
+    }
+}
+
+impl Completion {
+    fn complete(self, result: RpcResult) {
+        match self {
+            Completion::Callback(callback) => callback(result),
+            Completion::Blocking(tx) => {
+                //The caller may have given up waiting already
+                let _ = tx.send(result);
+            }
+            Completion::Future(completion) => completion.complete(result),
+        }
+    }
+}
+
+impl Responder {
+    pub fn channel(&self) -> &Arc<Channel> {
+        &self.channel
+    }
+
+    pub fn correlation_id(&self) -> u64 {
+        self.correlation_id
+    }
+
+    /// Answer the request
+    pub fn respond(self, payload: Vec<u8>) -> io::Result<()> {
+        self.send(RpcKind::Response, payload)
+    }
+
+    /// Answer the request with an error, which is reported to the caller as [RpcError::Remote]
+    pub fn respond_error(self, payload: Vec<u8>) -> io::Result<()> {
+        self.send(RpcKind::ErrorResponse, payload)
+    }
+
+    fn send(self, kind: RpcKind, payload: Vec<u8>) -> io::Result<()> {
+        let mut codec = VarintDelimitedCodec::new(self.max_message_size);
+
+        self.channel.write_encoded(&mut codec, RpcFrame::new(kind, self.correlation_id, payload))
+    }
+}
+
+impl Display for RpcError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcError::Timeout => write!(f, "Request timed out"),
+            RpcError::ChannelClosed => write!(f, "Channel was closed before the response arrived"),
+            RpcError::Remote(_) => write!(f, "Peer responded with an error"),
+            RpcError::Io(err) => write!(f, "Failed to send request: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register(pending: &PendingRequests, channel_id: usize, timeout: Duration) -> (u64, crossbeam_channel::Receiver<RpcResult>) {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+
+        (pending.register(channel_id, timeout, Completion::Blocking(tx)), rx)
+    }
+
+    #[test]
+    fn responses_complete_the_request_of_their_channel() {
+        let pending = PendingRequests::new();
+
+        let (first, first_rx) = register(&pending, 1, Duration::from_secs(60));
+        let (second, second_rx) = register(&pending, 2, Duration::from_secs(60));
+
+        assert_ne!(first, second);
+
+        //Through the wrong channel, or for requests we don't know about
+        pending.complete(2, first, Ok(b"wrong".to_vec()));
+        pending.complete(1, 1000, Ok(b"unknown".to_vec()));
+
+        assert!(first_rx.try_recv().is_err());
+        assert_eq!(pending.pending.lock_safe().len(), 2);
+
+        pending.complete(2, second, Err(RpcError::Remote(b"failed".to_vec())));
+        pending.complete(1, first, Ok(b"first".to_vec()));
+
+        assert_eq!(first_rx.try_recv().unwrap().unwrap(), b"first");
+        assert!(matches!(second_rx.try_recv().unwrap(), Err(RpcError::Remote(payload)) if payload == b"failed"));
+        assert!(pending.pending.lock_safe().is_empty());
+    }
+
+    #[test]
+    fn requests_time_out() {
+        let pending = Arc::new(PendingRequests::new());
+
+        PendingRequests::start_timeout_checker(Arc::downgrade(&pending)).unwrap();
+
+        let (correlation_id, rx) = register(&pending, 1, Duration::from_millis(20));
+        let (_, waiting_rx) = register(&pending, 1, Duration::from_secs(60));
+
+        assert!(matches!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), Err(RpcError::Timeout)));
+
+        //A late response is ignored
+        pending.complete(1, correlation_id, Ok(Vec::new()));
+
+        assert!(waiting_rx.try_recv().is_err());
+        assert_eq!(pending.pending.lock_safe().len(), 1);
+    }
+
+    #[test]
+    fn closing_a_channel_fails_its_requests() {
+        let pending = PendingRequests::new();
+
+        let (_, first_rx) = register(&pending, 1, Duration::from_secs(60));
+        let (_, second_rx) = register(&pending, 1, Duration::from_secs(60));
+        let (_, other_rx) = register(&pending, 2, Duration::from_secs(60));
+
+        pending.fail_channel(1);
+
+        assert!(matches!(first_rx.try_recv().unwrap(), Err(RpcError::ChannelClosed)));
+        assert!(matches!(second_rx.try_recv().unwrap(), Err(RpcError::ChannelClosed)));
+        assert!(other_rx.try_recv().is_err());
+        assert_eq!(pending.pending.lock_safe().len(), 1);
+    }
+}
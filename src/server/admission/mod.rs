@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use crate::config::ConnectionLimits;
+use crate::util::LockExt;
+
+/// Why a connection was not admitted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Total,
+    PerIp,
+}
+
+/// Keeps track of the connections a server currently has, so we can enforce the
+/// [ConnectionLimits] at accept time
+pub(crate) struct ConnectionTracker {
+    limits: ConnectionLimits,
+    connections: Mutex<TrackedConnections>,
+}
+
+#[derive(Default)]
+struct TrackedConnections {
+    total: usize,
+    per_ip: BTreeMap<IpAddr, usize>,
+}
+
+impl ConnectionTracker {
+    pub(crate) fn new(limits: ConnectionLimits) -> Self {
+        ConnectionTracker {
+            limits,
+            connections: Mutex::new(TrackedConnections::default()),
+        }
+    }
+
+    pub(crate) fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    /// Attempt to admit a new connection from the given address.
+    /// If it's admitted, it counts towards the limits until it's released
+    pub(crate) fn try_admit(&self, ip: IpAddr) -> Result<(), LimitExceeded> {
+        let mut connections = self.connections.lock_safe();
+
+        if let Some(max) = self.limits.max_connections() {
+            if connections.total >= max {
+                return Err(LimitExceeded::Total);
+            }
+        }
+
+        let from_ip = connections.per_ip.get(&ip).copied().unwrap_or(0);
+
+        if let Some(max) = self.limits.max_connections_per_ip() {
+            if from_ip >= max {
+                return Err(LimitExceeded::PerIp);
+            }
+        }
+
+        connections.total += 1;
+        connections.per_ip.insert(ip, from_ip + 1);
+
+        Ok(())
+    }
+
+    /// Release a connection that was previously admitted
+    pub(crate) fn release(&self, ip: IpAddr) {
+        let mut connections = self.connections.lock_safe();
+
+        connections.total = connections.total.saturating_sub(1);
+
+        if let Some(from_ip) = connections.per_ip.get_mut(&ip) {
+            *from_ip -= 1;
+
+            if *from_ip == 0 {
+                connections.per_ip.remove(&ip);
+            }
+        }
+    }
+
+    /// Whether we can still accept connections without exceeding the total limit
+    pub(crate) fn has_capacity(&self) -> bool {
+        match self.limits.max_connections() {
+            Some(max) => self.connections.lock_safe().total < max,
+            None => true
+        }
+    }
+
+    pub(crate) fn connection_count(&self) -> usize {
+        self.connections.lock_safe().total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::LimitPolicy;
+    use super::*;
+
+    #[test]
+    fn connections_count_until_released() {
+        let tracker = ConnectionTracker::new(ConnectionLimits::new(Some(2), None, LimitPolicy::Reject));
+
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        assert!(tracker.try_admit(ip).is_ok());
+        assert!(tracker.try_admit(ip).is_ok());
+        assert_eq!(tracker.connection_count(), 2);
+        assert!(!tracker.has_capacity());
+
+        assert_eq!(tracker.try_admit("10.0.0.2".parse().unwrap()), Err(LimitExceeded::Total));
+
+        tracker.release(ip);
+
+        assert!(tracker.has_capacity());
+        assert!(tracker.try_admit("10.0.0.2".parse().unwrap()).is_ok());
+
+        tracker.release(ip);
+        tracker.release("10.0.0.2".parse().unwrap());
+
+        assert_eq!(tracker.connection_count(), 0);
+
+        //Releasing more than was admitted doesn't underflow
+        tracker.release(ip);
+
+        assert_eq!(tracker.connection_count(), 0);
+    }
+
+    #[test]
+    fn connections_are_limited_per_ip() {
+        let tracker = ConnectionTracker::new(ConnectionLimits::new(None, Some(1), LimitPolicy::Reject));
+
+        let first: IpAddr = "10.0.0.1".parse().unwrap();
+        let second: IpAddr = "::1".parse().unwrap();
+
+        assert!(tracker.try_admit(first).is_ok());
+        assert_eq!(tracker.try_admit(first), Err(LimitExceeded::PerIp));
+        assert!(tracker.try_admit(second).is_ok());
+
+        tracker.release(first);
+
+        assert!(tracker.try_admit(first).is_ok());
+        assert_eq!(tracker.try_admit(second), Err(LimitExceeded::PerIp));
+        assert_eq!(tracker.connection_count(), 2);
+        assert!(tracker.has_capacity());
+    }
+}
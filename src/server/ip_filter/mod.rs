@@ -0,0 +1,186 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A block of IP addresses in CIDR notation (for example `10.0.0.0/8` or `fd00::/8`).
+/// A bare address is a block with just that address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+/// Decides which peers may connect, based on their IP address.
+/// Addresses that match a deny rule are always refused. If there are allow rules,
+/// only the addresses that match at least one of them are accepted
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> io::Result<Self> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        if prefix_len > max_len {
+            return Err(io::Error::new(ErrorKind::InvalidInput,
+                                      format!("Prefix length {} is too long for {}", prefix_len, addr)));
+        }
+
+        Ok(Cidr {
+            addr,
+            prefix_len,
+        })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, normalize(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(network) as u128, u32::from(ip) as u128, self.prefix_len, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(network), u128::from(ip), self.prefix_len, 128)
+            }
+            _ => false
+        }
+    }
+}
+
+/// Peers connecting to a dual stack socket over IPv4 show up as IPv4 mapped IPv6 addresses,
+/// we want those to match the IPv4 rules
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        IpAddr::V4(_) => ip
+    }
+}
+
+fn prefix_matches(network: u128, ip: u128, prefix_len: u8, bits: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+
+    let shift = bits - prefix_len;
+
+    (network >> shift) == (ip >> shift)
+}
+
+impl FromStr for Cidr {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || io::Error::new(ErrorKind::InvalidInput, format!("Invalid CIDR block {}", s));
+
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => {
+                let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+
+                (addr, prefix_len.trim().parse().map_err(|_| invalid())?)
+            }
+            None => {
+                let addr: IpAddr = s.trim().parse().map_err(|_| invalid())?;
+
+                (addr, if addr.is_ipv4() { 32 } else { 128 })
+            }
+        };
+
+        Cidr::new(addr, prefix_len)
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl IpFilter {
+    /// A filter that accepts every address
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        IpFilter {
+            allow,
+            deny,
+        }
+    }
+
+    /// Build a filter from rules in CIDR notation
+    pub fn parse(allow: &[&str], deny: &[&str]) -> io::Result<Self> {
+        let parse_all = |rules: &[&str]| rules.iter()
+            .map(|rule| rule.parse())
+            .collect::<io::Result<Vec<Cidr>>>();
+
+        Ok(Self::new(parse_all(allow)?, parse_all(deny)?))
+    }
+
+    pub fn allow(&self) -> &[Cidr] {
+        &self.allow
+    }
+
+    pub fn deny(&self) -> &[Cidr] {
+        &self.deny
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|rule| rule.contains(ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|rule| rule.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_matching() {
+        let block: Cidr = "10.1.0.0/16".parse().unwrap();
+
+        assert!(block.contains(ip("10.1.200.3")));
+        assert!(!block.contains(ip("10.2.0.1")));
+        assert!(block.contains(ip("::ffff:10.1.0.1")));
+
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+
+        assert!(v6.contains(ip("fd12::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+        assert!(!v6.contains(ip("10.1.0.1")));
+
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let filter = IpFilter::parse(&["10.0.0.0/8", "::1"], &["10.0.0.66"]).unwrap();
+
+        assert!(filter.is_allowed(ip("10.20.30.40")));
+        assert!(filter.is_allowed(ip("::1")));
+        assert!(!filter.is_allowed(ip("10.0.0.66")));
+        assert!(!filter.is_allowed(ip("192.168.1.1")));
+        assert!(IpFilter::allow_all().is_allowed(ip("192.168.1.1")));
+    }
+}
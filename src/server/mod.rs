@@ -1,21 +1,74 @@
-mod tcp_server;
+pub mod tcp_server;
+mod admission;
+pub mod ip_filter;
 
+pub use crate::event_group::overload::OverloadStats;
+
+use std::fs::File;
 use std::io;
+use std::io::{ErrorKind, Write};
 use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use log::{debug, error, info, warn};
 use polling::{Event, Poller};
 use crate::channel::{Channel, ChannelNetwork};
-use crate::config::ServerConfig;
+use crate::channel::id::next_channel_id;
+use crate::config::{LimitPolicy, ServerConfig};
+use crate::error::{Result, RusttyError};
 use crate::event_group::{EventGroup, EventGroupHandle};
-use std::io::Result;
+use crate::server::admission::ConnectionTracker;
+use crate::server::ip_filter::IpFilter;
+use crate::util::{ChannelHandler, LockExt};
+use crate::util::pool::PoolStats;
+
+/// How often we check if we can resume accepting connections, when accepting is paused
+const ACCEPT_PAUSE_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long we stop accepting after running out of file descriptors, doubling
+/// every time it happens again in a row
+const ACCEPT_BACKOFF_BASE: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
 
-struct Server {
+/// The file we keep open so we have a file descriptor to spare when we run out of them
+const RESERVED_FD_PATH: &str = "/dev/null";
+
+pub struct Server {
     config: ServerConfig,
     event_group: EventGroupHandle
 }
 
+/// A handle to a running server, used to control it while it's running
+#[derive(Clone)]
+pub struct ServerHandle {
+    shared: Arc<ServerShared>,
+    event_group: EventGroupHandle,
+}
+
+/// The state shared between the accept thread and the server handles
+struct ServerShared {
+    ip_filter: RwLock<Arc<IpFilter>>,
+    //Whether we are still accepting connections
+    running: AtomicBool,
+    //The error that stopped the server, if any
+    failure: Mutex<Option<Arc<RusttyError>>>,
+}
+
+/// How an error returned when accepting a connection should be handled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AcceptErrorKind {
+    /// Only affects the connection we were accepting (or nothing at all), so we just keep going
+    Transient,
+    /// We ran out of file descriptors, so we have to wait for some to be released
+    OutOfDescriptors,
+    /// The listener is no longer usable
+    Fatal,
+}
+
 pub trait NetworkServer {
 
-    fn bind<A: ToSocketAddrs>(addr: A) -> Result<Box<dyn NetworkServer>>;
+    fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> where Self: Sized;
 
     fn accept(&mut self);
 
@@ -23,13 +76,24 @@ pub trait NetworkServer {
 
 impl Server {
 
-    pub fn bind(config: ServerConfig) {
-        let handle = EventGroup::initialize_event_group(0, config.base_config().event_loop_thread_count());
+    /// Bind the server, handling the accepted connections with the given handler
+    pub fn bind<C>(config: ServerConfig, channel_handler: C) -> Result<ServerHandle>
+        where C: ChannelHandler + 'static {
+        Self::bind_with_handler(config, channel_handler, ())
+    }
 
+    /// Bind the server, also notifying the server handler of the events of the server itself
+    pub fn bind_with_handler<C, H>(config: ServerConfig, channel_handler: C, handler: H) -> Result<ServerHandle>
+        where C: ChannelHandler + 'static, H: ServerHandler + Send + 'static {
+        let handle = EventGroup::initialize_event_group(0, config.base_config(), Arc::new(channel_handler))?;
 
+        Server {
+            config,
+            event_group: handle,
+        }.begin(handler)
     }
 
-    fn begin(self) -> io::Result<()> {
+    fn begin<H>(self, handler: H) -> Result<ServerHandle> where H: ServerHandler + Send + 'static {
         let bind_result = TcpListener::bind((self.config.bind_addr(), self.config.port()));
 
         let tcp_listener = match bind_result {
@@ -39,26 +103,63 @@ impl Server {
 
             }
             Err(err) => {
-                return Err(err);
+                return Err(RusttyError::Bind(err));
             }
         };
 
-        tcp_listener.set_nonblocking(true).unwrap();
+        tcp_listener.set_nonblocking(true)?;
+
+        let key = 6;
+
+        let poller = Poller::new().map_err(RusttyError::Poller)?;
+
+        poller.add(&tcp_listener, Event::readable(key)).map_err(RusttyError::Poller)?;
+
+        let shared = Arc::new(ServerShared {
+            ip_filter: RwLock::new(Arc::new(self.config.ip_filter().clone())),
+            running: AtomicBool::new(true),
+            failure: Mutex::new(None),
+        });
+
+        let server_handle = ServerHandle {
+            shared: shared.clone(),
+            event_group: self.event_group.clone(),
+        };
 
         std::thread::Builder::new().name(format!("Server {:?}", self.config.bind_addr()))
             .spawn(move || {
 
-                let key = 6;
+                let tracker = Arc::new(ConnectionTracker::new(self.config.connection_limits().clone()));
 
-                let poller = Poller::new().unwrap();
+                let pause_on_limit = *tracker.limits().policy() == LimitPolicy::PauseAccepting;
 
-                poller.add(&tcp_listener, Event::readable(key)).unwrap();
+                //Whether we have stopped accepting connections because we reached the limit
+                let mut paused = false;
+
+                //A file descriptor we can give up when we run out of them, so we can
+                //still accept (and close) pending connections
+                let mut reserved_fd = File::open(RESERVED_FD_PATH).ok();
+
+                let mut backoff = ACCEPT_BACKOFF_BASE;
 
                 let mut events = Vec::new();
                 loop {
 
                     events.clear();
-                    poller.wait(&mut events, None).unwrap();
+
+                    //While paused, the listener is not registered for events, so we
+                    //just wake up periodically to check if there is room again
+                    let timeout = if paused { Some(ACCEPT_PAUSE_CHECK_INTERVAL) } else { None };
+
+                    if let Err(err) = poller.wait(&mut events, timeout) {
+                        if err.kind() == ErrorKind::Interrupted {
+                            continue;
+                        }
+
+                        shared.fail(&handler, RusttyError::Poller(err));
+
+                        return;
+                    }
 
                     for event in &events {
                         if event.key == key {
@@ -67,31 +168,209 @@ impl Server {
                             match tcp_conn {
                                 Ok((conn, addr)) => {
 
-                                    conn.set_nonblocking(true).expect("Failed to make connection non blocking");
+                                    backoff = ACCEPT_BACKOFF_BASE;
+
+                                    if !shared.ip_filter().is_allowed(addr.ip()) {
+                                        debug!("Refusing connection from {:?} as it's not allowed by the IP filter", addr);
+
+                                        continue;
+                                    }
+
+                                    if let Err(err) = conn.set_nonblocking(true) {
+                                        warn!("Failed to make the connection from {:?} non blocking, dropping it. {:?}", addr, err);
+
+                                        continue;
+                                    }
+
+                                    if let Err(exceeded) = tracker.try_admit(addr.ip()) {
+                                        debug!("Refusing connection from {:?} as it exceeds the {:?} connection limit", addr, exceeded);
+
+                                        if let LimitPolicy::RejectWithPayload(payload) = tracker.limits().policy() {
+                                            //Best effort, we don't want to wait for slow peers in the accept thread
+                                            let _ = (&conn).write(payload);
+                                        }
+
+                                        //Dropping the connection closes it
+                                        continue;
+                                    }
+
+                                    let channel_id = next_channel_id(self.config.base_config().channel_id_scheme());
 
-                                    let channel = Channel::new(1,
-                                    ChannelNetwork::new(addr, Box::new(conn)));
+                                    let network = ChannelNetwork::new(addr, Box::new(conn),
+                                                                      self.config.base_config().pending_tx_base_vec_size());
 
-                                    self.event_group.register_new_connection(channel)
+                                    let channel = Channel::new(channel_id, network, self.event_group.clone());
+
+                                    let tracker = tracker.clone();
+
+                                    channel.on_close(move || tracker.release(addr.ip()));
+
+                                    match self.event_group.register_new_connection(channel) {
+                                        Ok(_) => {}
+                                        //The event group has already counted and closed it, the next
+                                        //connections might find room again
+                                        Err(RusttyError::Overloaded) => {
+                                            debug!("Dropping connection from {:?} as the event group is overloaded", addr);
+                                        }
+                                        Err(err) => {
+                                            //The event group is gone, so we can't serve any more connections
+                                            shared.fail(&handler, err);
+
+                                            return;
+                                        }
+                                    }
                                 }
                                 Err(err) => {
+                                    match classify_accept_error(&err) {
+                                        AcceptErrorKind::Transient => {
+                                            debug!("Transient error while accepting a connection, retrying. {:?}", err);
+                                        }
+                                        AcceptErrorKind::OutOfDescriptors => {
+                                            warn!("Ran out of file descriptors while accepting connections, backing off for {:?}. {:?}", backoff, err);
+
+                                            //Give up our spare descriptor so we can take the pending connection
+                                            //out of the backlog and close it, instead of leaving the peer hanging
+                                            //and being woken up for it over and over
+                                            drop(reserved_fd.take());
+
+                                            if let Ok((conn, addr)) = tcp_listener.accept() {
+                                                debug!("Closing connection from {:?} as we have no file descriptors for it", addr);
+
+                                                drop(conn);
+                                            }
+
+                                            reserved_fd = File::open(RESERVED_FD_PATH).ok();
+
+                                            std::thread::sleep(backoff);
+
+                                            backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                                        }
+                                        AcceptErrorKind::Fatal => {
+                                            shared.fail(&handler, RusttyError::Io(err));
 
+                                            return;
+                                        }
+                                    }
                                 }
                             }
                         }
                     }
 
 
-                    poller.modify(&tcp_listener, Event::readable(key)).unwrap();
+                    if pause_on_limit && !tracker.has_capacity() {
+                        if !paused {
+                            warn!("Reached the limit of {} connections, pausing accepts", tracker.connection_count());
+                        }
+
+                        paused = true;
+
+                        //Don't re-arm the listener, new connections wait in the backlog
+                        continue;
+                    }
+
+                    if paused {
+                        info!("Resuming accepts with {} connections", tracker.connection_count());
+
+                        paused = false;
+                    }
+
+                    if let Err(err) = poller.modify(&tcp_listener, Event::readable(key)) {
+                        shared.fail(&handler, RusttyError::Poller(err));
+
+                        return;
+                    }
                 }
 
-            }).expect("Failed to launch server monitor thread thread");
+            })?;
+
+        Ok(server_handle)
+    }
+
+}
+
+fn classify_accept_error(err: &io::Error) -> AcceptErrorKind {
+    match err.raw_os_error() {
+        Some(libc::EMFILE) | Some(libc::ENFILE) => return AcceptErrorKind::OutOfDescriptors,
+        _ => {}
+    }
+
+    match err.kind() {
+        //The peer gave up on the connection before we accepted it
+        ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset
+        | ErrorKind::Interrupted | ErrorKind::WouldBlock => AcceptErrorKind::Transient,
+        _ => AcceptErrorKind::Fatal
+    }
+}
+
+impl ServerHandle {
+    /// Replace the IP filter. Only affects connections accepted from now on
+    pub fn set_ip_filter(&self, ip_filter: IpFilter) {
+        *self.shared.ip_filter.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(ip_filter);
+    }
+
+    pub fn ip_filter(&self) -> Arc<IpFilter> {
+        self.shared.ip_filter()
+    }
+
+    /// Whether the server is still accepting connections
+    pub fn is_running(&self) -> bool {
+        self.shared.running.load(Ordering::SeqCst)
+    }
+
+    /// The error that stopped the server from accepting connections, if it has stopped
+    pub fn failure(&self) -> Option<Arc<RusttyError>> {
+        self.shared.failure.lock_safe().clone()
+    }
 
-        Ok(())
+    /// How often the read and write buffers of the connections were reused
+    pub fn buffer_pool_stats(&self) -> PoolStats {
+        self.event_group.buffer_pool().stats()
     }
 
+    /// How many events and connections were dropped because the event group was overloaded
+    pub fn overload_stats(&self) -> OverloadStats {
+        self.event_group.overload_stats()
+    }
 }
 
+impl ServerShared {
+    fn ip_filter(&self) -> Arc<IpFilter> {
+        self.ip_filter.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// The listener has failed and we can no longer accept connections.
+    /// Existing connections are not affected
+    fn fail<H>(&self, handler: &H, err: RusttyError) where H: ServerHandler {
+        error!("The server has stopped accepting connections because of {:?}", err);
+
+        handler.handle_server_failed(&err);
+
+        *self.failure.lock_safe() = Some(Arc::new(err));
+
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Handles the events of the server itself (not the events of the connections)
 pub trait ServerHandler {
 
+    /// The server has stopped accepting connections because of an error
+    fn handle_server_failed(&self, _err: &RusttyError) {}
+
+}
+
+impl ServerHandler for () {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_errors_are_classified() {
+        assert_eq!(classify_accept_error(&io::Error::from_raw_os_error(libc::EMFILE)), AcceptErrorKind::OutOfDescriptors);
+        assert_eq!(classify_accept_error(&io::Error::from_raw_os_error(libc::ENFILE)), AcceptErrorKind::OutOfDescriptors);
+        assert_eq!(classify_accept_error(&io::Error::from(ErrorKind::ConnectionAborted)), AcceptErrorKind::Transient);
+        assert_eq!(classify_accept_error(&io::Error::from(ErrorKind::Interrupted)), AcceptErrorKind::Transient);
+        assert_eq!(classify_accept_error(&io::Error::from(ErrorKind::InvalidInput)), AcceptErrorKind::Fatal);
+    }
 }
\ No newline at end of file
@@ -1,7 +1,12 @@
 use std::net::TcpStream;
 use crate::util::Stream;
 
-impl Stream for TcpStream {}
+impl Stream for TcpStream {
+
+    fn is_raw_socket(&self) -> bool {
+        true
+    }
+}
 
 pub struct TcpServerConfig {
     
@@ -1,16 +1,37 @@
-use std::error::Error;
+pub mod pool;
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::io::{Read, Write};
 use std::net::SocketAddr;
 use std::os::fd::AsRawFd;
-use std::sync::Arc;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
 use crate::channel::Channel;
+use crate::error::RusttyError;
 
 /// Trait object responsible for handling reported I/O events.
 /// The stream provided here must be in Non Blocking mode for this
 /// Library to work properly
 /// As such, when the operation is not ready to be completed an Error with
 /// ErrorKind::WouldBlock should be returned
-pub trait Stream: Read + Write + AsRawFd + Send {}
+pub trait Stream: Read + Write + AsRawFd + Send {
+
+    /// Whether the bytes written to the raw file descriptor go straight to the peer.
+    /// This allows us to hand transfers over to the kernel (with sendfile), which
+    /// streams that wrap the socket (like TLS) must not allow
+    fn is_raw_socket(&self) -> bool {
+        false
+    }
+}
+
+impl Stream for UnixStream {
+
+    fn is_raw_socket(&self) -> bool {
+        true
+    }
+}
 
 
 pub trait NetworkServer<T>: AsRawFd where T: Stream {
@@ -27,10 +48,38 @@ pub trait ChannelHandler: Sync + Send {
     /// he must also return a channel that is owned.
     fn handle_connection_established(&self, channel: Channel) -> Channel;
 
-    /// Handle a new message being received
+    /// Handle a new message being received.
+    /// The buffer comes from the buffer pool of the event group, so it should be given back
+    /// with [Channel::release_buffer] once it's no longer needed
     fn handle_message_received(&self, channel: Arc<Channel>, buf: Vec<u8>);
 
     /// Handle a connection being removed, either because of errors in the connection
     /// Or because of a request to remove it
-    fn handle_connection_removed(&self, channel: Arc<Channel>, err: Option<impl Error>);
-}
\ No newline at end of file
+    fn handle_connection_removed(&self, channel: Arc<Channel>, err: Option<RusttyError>);
+}
+
+/// Locking that survives poisoning.
+/// A panic while holding one of our locks (for example, inside a handler) should only
+/// affect the connection it happened on, not take down every other user of the lock
+pub(crate) trait LockExt<T: ?Sized> {
+    fn lock_safe(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T: ?Sized> LockExt<T> for Mutex<T> {
+    fn lock_safe(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Generate a random 64 bit value.
+/// This is not cryptographically secure, it's only meant to avoid predictable values
+/// (such as websocket masking keys) without pulling in an extra dependency
+pub(crate) fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = RandomState::new().build_hasher();
+
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+
+    hasher.finish()
+}
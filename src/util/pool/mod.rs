@@ -0,0 +1,130 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::util::LockExt;
+
+//The smallest and largest size classes of the pool, as powers of two (1 KiB to 64 KiB)
+const MIN_CLASS_SHIFT: u32 = 10;
+const MAX_CLASS_SHIFT: u32 = 16;
+
+/// How often buffers were reused from a pool instead of being allocated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// The buffers that were taken from the pool
+    pub hits: u64,
+    /// The buffers that had to be allocated, as the pool had none of the requested size
+    pub misses: u64,
+}
+
+/// A pool of byte buffers, shared by the workers of an event group.
+/// Buffers are kept in power of two size classes. Requests are rounded up to the size
+/// of their class and only served by buffers of that class, so we never hand out
+/// buffers much larger than what was asked for.
+/// Each class keeps at most `capacity` buffers, the rest are freed when they are released
+pub(crate) struct BufferPool {
+    classes: Vec<Mutex<Vec<Vec<u8>>>>,
+    capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BufferPool {
+    pub(crate) fn new(capacity: usize) -> Self {
+        BufferPool {
+            classes: (MIN_CLASS_SHIFT..=MAX_CLASS_SHIFT).map(|_| Mutex::new(Vec::new())).collect(),
+            capacity,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Take an empty buffer with room for at least `size` bytes.
+    /// It should be given back with [BufferPool::release] once it's no longer needed
+    pub(crate) fn lease(&self, size: usize) -> Vec<u8> {
+        let shift = size.max(1).next_power_of_two().trailing_zeros().max(MIN_CLASS_SHIFT);
+
+        if shift > MAX_CLASS_SHIFT {
+            //Too large to be pooled
+            self.misses.fetch_add(1, Ordering::Relaxed);
+
+            return Vec::with_capacity(size);
+        }
+
+        if let Some(buf) = self.classes[(shift - MIN_CLASS_SHIFT) as usize].lock_safe().pop() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+
+            return buf;
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        Vec::with_capacity(1 << shift)
+    }
+
+    /// Give a buffer back to the pool, so it can be leased again.
+    /// Any buffer can be released, not only the ones that were leased
+    pub(crate) fn release(&self, mut buf: Vec<u8>) {
+        let capacity = buf.capacity();
+
+        //Buffers go in the largest class they can fully serve. We don't keep the ones
+        //that would be wasting more than half of their memory in the largest class
+        if !(1 << MIN_CLASS_SHIFT..1 << (MAX_CLASS_SHIFT + 1)).contains(&capacity) {
+            return;
+        }
+
+        let shift = usize::BITS - 1 - capacity.leading_zeros();
+
+        let mut class = self.classes[(shift - MIN_CLASS_SHIFT) as usize].lock_safe();
+
+        if class.len() < self.capacity {
+            buf.clear();
+
+            class.push(buf);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_reused_by_size_class() {
+        let pool = BufferPool::new(1);
+
+        let small = pool.lease(100);
+        let large = pool.lease(5000);
+
+        assert_eq!(small.capacity(), 1024);
+        assert_eq!(large.capacity(), 8192);
+        assert_eq!(pool.stats(), PoolStats { hits: 0, misses: 2 });
+
+        pool.release(large);
+        //Over the capacity of the class
+        pool.release(vec![0; 8192]);
+
+        //Only the buffers of the requested class are handed out
+        assert_eq!(pool.lease(3000).capacity(), 4096);
+        assert_eq!(pool.stats(), PoolStats { hits: 0, misses: 3 });
+
+        assert_eq!(pool.lease(8000).capacity(), 8192);
+        assert_eq!(pool.stats(), PoolStats { hits: 1, misses: 3 });
+
+        //Too large to be pooled
+        pool.release(vec![0; 1 << 20]);
+
+        assert_eq!(pool.lease(1 << 20).capacity(), 1 << 20);
+        assert_eq!(pool.stats(), PoolStats { hits: 1, misses: 4 });
+
+        pool.release(small);
+
+        assert_eq!(pool.lease(1).capacity(), 1024);
+        assert_eq!(pool.stats(), PoolStats { hits: 2, misses: 4 });
+    }
+}